@@ -1,4 +1,5 @@
-use std::thread::sleep_ms;
+use std::thread::sleep;
+use std::time::Duration;
 
 use log::info;
 use serde::{Deserialize, Serialize};
@@ -74,14 +75,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await?;
     let mut body: Vec<Library> = res.json().await?;
 
-    body = body
-        .into_iter()
-        .filter(|entry| entry.name == *"Household")
-        .collect();
+    body.retain(|entry| entry.name == *"Household");
 
     println!("Body:\n\n{:#?}", body);
 
-    let id = match body.get(0) {
+    let id = match body.first() {
         Some(lib) => &lib.id,
         _ => return Ok(()),
     };
@@ -101,7 +99,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("Body:\n\n{:#?}", body);
 
-    let fname = match body.get(0) {
+    let fname = match body.first() {
         Some(f) => &f.name,
         _ => return Ok(()),
     };
@@ -150,8 +148,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         for (i, c) in body.chars().enumerate() {
             info!("{i} {c}");
         }
-        sleep_ms(10000);
+        sleep(Duration::from_secs(10));
     }
-
-    Ok(())
 }
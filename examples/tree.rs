@@ -0,0 +1,43 @@
+//! List everything in every library, walking the directories concurrently.
+//!
+//! Takes credentials from `SEAFILE_SERVER`, `SEAFILE_USERNAME` and `SEAFILE_PASSWORD`.
+
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::task::JoinSet;
+use upgraded_giggle::asyncapi::{AsyncSeafileAPI, Result};
+use upgraded_giggle::seafileapi::LibraryEntry;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let api = Arc::new(AsyncSeafileAPI::new(
+        &env::var("SEAFILE_SERVER")?,
+        &env::var("SEAFILE_USERNAME")?,
+        &env::var("SEAFILE_PASSWORD")?,
+    ));
+
+    let mut listings = JoinSet::new();
+    let list = |listings: &mut JoinSet<_>, library: String, id: String, dir: PathBuf| {
+        let api = api.clone();
+        listings.spawn(async move {
+            let entries: Result<Vec<LibraryEntry>> = api.get_library_content(&id, &dir).await;
+            (library, id, dir, entries)
+        });
+    };
+    for library in api.get_libraries().await? {
+        list(&mut listings, library.name, library.id, PathBuf::from("/"));
+    }
+    while let Some(listing) = listings.join_next().await {
+        let (library, id, dir, entries) = listing?;
+        for entry in entries? {
+            let path = dir.join(&entry.name);
+            println!("{}:{}", library, path.display());
+            if entry.entry_type == "dir" {
+                list(&mut listings, library.clone(), id.clone(), path);
+            }
+        }
+    }
+    Ok(())
+}
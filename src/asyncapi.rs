@@ -0,0 +1,1129 @@
+//! The Seafile API on reqwest's async client. `SeafileAPI` runs it to completion on
+//! `runtime()` for blocking callers, and background work is spawned onto that runtime.
+//!
+//! Errors are `Send` and `Sync`, so the futures can be spawned onto a runtime and many
+//! operations run at once.
+
+use crate::cache::TtlCache;
+use crate::seafileapi::{
+    is_connection_reset, is_link_refused, is_retryable_status, retry_after, AccountInfo,
+    AuthResponse, ClientOptions, Commit, CommitDir, CommitEntry, FileHistory, FileRevision,
+    Library, LibraryEntry, LibraryHistory, Offline, Secret, ShareLink, Trash, TrashItem,
+    UploadLink, UploadedBytes, UploadedFile, LINK_TTL, MAX_RETRY_DELAY, OFFLINE_RETRY,
+    UPLOAD_ATTEMPTS, UPLOAD_CHUNK_SIZE,
+};
+pub use crate::seafileapi::{is_offline, Error, Result};
+use bytes::Bytes;
+use log::{debug, info, warn};
+use reqwest::header::HeaderValue;
+use reqwest::multipart::{Form, Part};
+use reqwest::{RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tokio::runtime::Runtime;
+
+/// The runtime shared by blocking callers of `SeafileAPI` and by background work. It lives as
+/// long as the process, so it is never dropped from one of its own tasks.
+pub fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("seafile-io")
+            .build()
+            .expect("tokio runtime")
+    })
+}
+
+#[derive(Debug)]
+pub struct AsyncSeafileAPI {
+    client: reqwest::Client,
+    options: ClientOptions,
+    /// The API token, once logged in
+    token: RwLock<Option<Secret>>,
+    libraries: RwLock<Option<Vec<Library>>>,
+    /// Download links by library id, path and file id
+    links: TtlCache<(String, PathBuf, String), String>,
+    /// When the server was last tried, while it is unreachable
+    offline: Mutex<Option<Instant>>,
+    server: String,
+    username: String,
+    password: Secret,
+}
+
+impl AsyncSeafileAPI {
+    pub fn new(server: &str, username: &str, password: &str) -> Self {
+        Self::with_options(server, username, password, ClientOptions::default())
+            .expect("HTTP client with default options")
+    }
+
+    pub fn with_options(
+        server: &str,
+        username: &str,
+        password: &str,
+        options: ClientOptions,
+    ) -> Result<Self> {
+        Ok(Self {
+            client: options.client()?,
+            options,
+            token: RwLock::new(None),
+            libraries: RwLock::new(None),
+            links: TtlCache::new(LINK_TTL),
+            offline: Mutex::new(None),
+            server: server.to_string(),
+            username: username.to_string(),
+            password: Secret(password.to_string()),
+        })
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Whether the server was reachable when last tried.
+    pub fn is_online(&self) -> bool {
+        self.offline.lock().unwrap().is_none()
+    }
+
+    /// Ping the server, waiting at most `read_timeout` for it to answer, or `OFFLINE_RETRY` if
+    /// requests may wait forever: FUSE threads wait on pings while offline.
+    pub async fn ping(&self) -> Result<()> {
+        let url = format!("{}/api2/ping/", self.server);
+        let timeout = self.options.read_timeout.unwrap_or(OFFLINE_RETRY);
+        tokio::time::timeout(timeout, self.client.get(url).send())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no answer to ping"))??
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Send `request`, keeping track of whether the server is reachable. While it is not,
+    /// requests fail with `Offline` without trying, except for a ping every `OFFLINE_RETRY`.
+    ///
+    /// Idempotent requests are retried, with backoff, when the server is overloaded, fails,
+    /// or drops the connection.
+    ///
+    /// `read_timeout` bounds the wait for the response; `chunk` bounds each wait for its body.
+    async fn send(&self, mut request: RequestBuilder) -> Result<Response> {
+        if !self.reachable().await {
+            return Err(Box::new(Offline));
+        }
+        let idempotent = request
+            .try_clone()
+            .and_then(|request| request.build().ok())
+            .is_some_and(|request| request.method().is_idempotent());
+        let mut attempt = 0;
+        loop {
+            let retry = match idempotent && attempt < self.options.retries {
+                // Requests with streamed bodies cannot be cloned, and so are not retried
+                true => request.try_clone(),
+                false => None,
+            };
+            let result = match self.options.read_timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, request.send()).await {
+                    Ok(result) => result.map_err(Error::from),
+                    Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "no response").into()),
+                },
+                None => request.send().await.map_err(Error::from),
+            };
+            let retry = match (retry, &result) {
+                (Some(retry), Ok(res)) if is_retryable_status(res.status()) => {
+                    let delay = retry_after(res.headers()).map(|delay| delay.min(MAX_RETRY_DELAY));
+                    Some((retry, delay, res.status().to_string()))
+                }
+                (Some(retry), Err(e)) => match e.downcast_ref::<reqwest::Error>() {
+                    Some(e) if is_connection_reset(e) => Some((retry, None, e.to_string())),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some((retry, delay, reason)) = retry {
+                let delay = delay.unwrap_or_else(|| self.options.backoff(attempt));
+                debug!("retrying in {:?}: {}", delay, reason);
+                tokio::time::sleep(delay).await;
+                request = retry;
+                attempt += 1;
+                continue;
+            }
+            return match result {
+                Ok(res) => Ok(res),
+                Err(e) => {
+                    if is_offline(&e) {
+                        let mut offline = self.offline.lock().unwrap();
+                        if offline.is_none() {
+                            info!("server unreachable, going offline: {}", e);
+                            *offline = Some(Instant::now());
+                        }
+                    }
+                    Err(e)
+                }
+            };
+        }
+    }
+
+    /// Whether to try the server, pinging it first if it was unreachable a while ago.
+    async fn reachable(&self) -> bool {
+        {
+            let mut offline = self.offline.lock().unwrap();
+            match *offline {
+                None => return true,
+                Some(tried) if tried.elapsed() < OFFLINE_RETRY => return false,
+                Some(_) => *offline = Some(Instant::now()),
+            }
+        }
+        if let Err(e) = self.ping().await {
+            debug!("still offline: {}", e);
+            return false;
+        }
+        info!("server reachable again, going online");
+        *self.offline.lock().unwrap() = None;
+        true
+    }
+
+    /// The next part of the body of `res`, or `None` at its end, waiting at most
+    /// `read_timeout` for it.
+    pub(crate) async fn chunk(&self, res: &mut Response) -> Result<Option<Bytes>> {
+        let chunk = match self.options.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, res.chunk())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "body stalled"))?,
+            None => res.chunk().await,
+        };
+        Ok(chunk?)
+    }
+
+    async fn bytes(&self, mut res: Response) -> Result<Bytes> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.chunk(&mut res).await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body.into())
+    }
+
+    async fn text(&self, res: Response) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.bytes(res).await?).into_owned())
+    }
+
+    async fn json<T: DeserializeOwned>(&self, res: Response) -> Result<T> {
+        Ok(serde_json::from_slice(&self.bytes(res).await?)?)
+    }
+
+    /// The `Authorization` header for requests, logging in first if need be. It is marked
+    /// sensitive, so it is left out of debug output as the token is.
+    async fn login(&self) -> Result<HeaderValue> {
+        let token = self.token.read().unwrap().clone();
+        let token = match token {
+            Some(token) => token,
+            None => {
+                let params = [("username", &self.username), ("password", &self.password.0)];
+                let url = format!("{}/api2/auth-token/", self.server);
+                let res = self.send(self.client.post(url).form(&params)).await?;
+                let body: AuthResponse = self.json(res).await?;
+                let token = Secret(body.token);
+                *self.token.write().unwrap() = Some(token.clone());
+                token
+            }
+        };
+        let mut authorization = HeaderValue::from_str(&format!("Token {}", token.0))?;
+        authorization.set_sensitive(true);
+        Ok(authorization)
+    }
+
+    pub async fn get_account_info(&self) -> Result<AccountInfo> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/account/info/", self.server);
+        let res = self
+            .send(self.client.get(url).header("Authorization", &authorization))
+            .await?
+            .error_for_status()?;
+        self.json(res).await
+    }
+
+    pub async fn get_libraries(&self) -> Result<Vec<Library>> {
+        if let Some(libraries) = &*self.libraries.read().unwrap() {
+            return Ok(libraries.to_vec());
+        }
+        self.refresh_libraries().await
+    }
+
+    /// Fetch the libraries afresh, replacing those `get_libraries` keeps.
+    pub async fn refresh_libraries(&self) -> Result<Vec<Library>> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/", self.server);
+        let res = self
+            .send(self.client.get(url).header("Authorization", &authorization))
+            .await?;
+        let body: Vec<Library> = self.json(res).await?;
+        *self.libraries.write().unwrap() = Some(body.clone());
+        Ok(body)
+    }
+
+    pub async fn get_library_content(&self, id: &str, path: &Path) -> Result<Vec<LibraryEntry>> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/{}/dir/", self.server, id);
+
+        debug!("url: {}, {:?}", url, ("p", path));
+
+        let res = self
+            .send(
+                self.client
+                    .get(&url)
+                    .query(&[("p", path)])
+                    .header("Authorization", &authorization),
+            )
+            .await?
+            .error_for_status()?;
+        self.json(res).await
+    }
+
+    pub async fn create_file(&self, id: &str, path: &Path) -> Result<String> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/{}/file/", self.server, id);
+
+        debug!("url: {}, {:?}", url, ("p", path));
+
+        let res = self
+            .send(
+                self.client
+                    .post(&url)
+                    .body("operation=create")
+                    .header(
+                        reqwest::header::CONTENT_TYPE,
+                        "application/x-www-form-urlencoded",
+                    )
+                    .query(&[("p", path)])
+                    .header("Authorization", &authorization),
+            )
+            .await?;
+        self.text(res).await
+    }
+
+    pub async fn create_new_directory(&self, id: &str, path: &Path) -> Result<String> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/{}/dir/", self.server, id);
+
+        debug!("url: {}, {:?}", url, ("p", path));
+
+        let res = self
+            .send(
+                self.client
+                    .post(&url)
+                    .body("operation=mkdir")
+                    .header(
+                        reqwest::header::CONTENT_TYPE,
+                        "application/x-www-form-urlencoded",
+                    )
+                    .query(&[("p", path)])
+                    .header("Authorization", &authorization),
+            )
+            .await?;
+        self.text(res.error_for_status()?).await
+    }
+
+    pub async fn delete_directory(&self, id: &str, path: &Path) -> Result<String> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/{}/dir/", self.server, id);
+
+        debug!("url: {}, {:?}", url, ("p", path));
+
+        let res = self
+            .send(
+                self.client
+                    .delete(&url)
+                    .query(&[("p", path)])
+                    .header("Authorization", &authorization),
+            )
+            .await?;
+        self.text(res.error_for_status()?).await
+    }
+
+    pub async fn delete_file(&self, id: &str, path: &Path) -> Result<String> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/{}/file/", self.server, id);
+
+        debug!("url: {}, {:?}", url, ("p", path));
+
+        let res = self
+            .send(
+                self.client
+                    .delete(&url)
+                    .query(&[("p", path)])
+                    .header("Authorization", &authorization),
+            )
+            .await?
+            .error_for_status()?;
+        self.text(res).await
+    }
+
+    /// Lock the file at `path` against changes by anyone else (Seafile Professional).
+    pub async fn lock_file(&self, id: &str, path: &Path) -> Result<()> {
+        self.set_file_lock(id, path, "lock").await
+    }
+
+    pub async fn unlock_file(&self, id: &str, path: &Path) -> Result<()> {
+        self.set_file_lock(id, path, "unlock").await
+    }
+
+    async fn set_file_lock(&self, id: &str, path: &Path, operation: &str) -> Result<()> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/{}/file/", self.server, id);
+
+        debug!(
+            "url: {}, {:?}, {:?}",
+            url,
+            ("p", path),
+            ("operation", operation)
+        );
+
+        self.send(
+            self.client
+                .put(&url)
+                .query(&[("p", path)])
+                .form(&[("operation", operation)])
+                .header("Authorization", &authorization),
+        )
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    pub async fn get_download_link(&self, id: &str, path: &Path) -> Result<String> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/{}/file/", self.server, id);
+
+        debug!("url: {}, {:?}, {:?}", url, ("p", path), ("reuse", 1));
+
+        let res = self
+            .send(
+                self.client
+                    .get(&url)
+                    .query(&[("p", path)])
+                    .query(&[("reuse", 1)])
+                    .header("Authorization", &authorization),
+            )
+            .await?
+            .error_for_status()?;
+        self.json(res).await
+    }
+
+    /// Download link for the file at `path` with id `file_id`, reused for as long as it lasts.
+    pub async fn get_file_link(&self, id: &str, path: &Path, file_id: &str) -> Result<String> {
+        let key = (id.to_string(), path.to_path_buf(), file_id.to_string());
+        if let Some(link) = self.links.get(&key) {
+            return Ok(link);
+        }
+        let link = self.get_download_link(id, path).await?;
+        // Links to what the file used to be are no use any more
+        self.links
+            .retain(|(library_id, p, _)| !(library_id == id && p == path));
+        self.links.insert(key, link.clone());
+        Ok(link)
+    }
+
+    /// Download `size` bytes starting at `offset` of the file at `path` with id `file_id`,
+    /// through a reused download link, asking for a new link if the fileserver refuses it.
+    pub async fn download_file_range(
+        &self,
+        id: &str,
+        path: &Path,
+        file_id: &str,
+        offset: u64,
+        size: u64,
+    ) -> Result<Bytes> {
+        let link = self.get_file_link(id, path, file_id).await?;
+        match self.download_range(&link, offset, size).await {
+            Err(e) if is_link_refused(e.as_ref()) => {
+                debug!("download link for {:?} refused, asking again", path);
+                self.links
+                    .remove(&(id.to_string(), path.to_path_buf(), file_id.to_string()));
+                let link = self.get_file_link(id, path, file_id).await?;
+                self.download_range(&link, offset, size).await
+            }
+            result => result,
+        }
+    }
+
+    /// Revisions of the file at `path`, newest first.
+    pub async fn get_file_history(&self, id: &str, path: &Path) -> Result<Vec<FileRevision>> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/{}/file/history/", self.server, id);
+
+        debug!("url: {}, {:?}", url, ("p", path));
+
+        let res = self
+            .send(
+                self.client
+                    .get(&url)
+                    .query(&[("p", path)])
+                    .header("Authorization", &authorization),
+            )
+            .await?
+            .error_for_status()?;
+        let body: FileHistory = self.json(res).await?;
+        Ok(body.commits)
+    }
+
+    /// Download link for the file at `path` as it was in the commit `commit_id`.
+    pub async fn get_file_revision_link(
+        &self,
+        id: &str,
+        path: &Path,
+        commit_id: &str,
+    ) -> Result<String> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/{}/file/revision/", self.server, id);
+
+        debug!(
+            "url: {}, {:?}, {:?}",
+            url,
+            ("p", path),
+            ("commit_id", commit_id)
+        );
+
+        let res = self
+            .send(
+                self.client
+                    .get(&url)
+                    .query(&[("p", path)])
+                    .query(&[("commit_id", commit_id)])
+                    .header("Authorization", &authorization),
+            )
+            .await?
+            .error_for_status()?;
+        self.json(res).await
+    }
+
+    /// Commits of the library, newest first.
+    pub async fn get_library_history(&self, id: &str) -> Result<Vec<Commit>> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/{}/history/", self.server, id);
+        let mut commits = Vec::new();
+
+        for page in 1.. {
+            debug!("url: {}, {:?}", url, ("page", page));
+
+            let res = self
+                .send(
+                    self.client
+                        .get(&url)
+                        .query(&[("page", page), ("per_page", 100)])
+                        .header("Authorization", &authorization),
+                )
+                .await?
+                .error_for_status()?;
+            let body: LibraryHistory = self.json(res).await?;
+            commits.extend(body.commits);
+            if !body.page_next {
+                break;
+            }
+        }
+        Ok(commits)
+    }
+
+    /// Entries of the directory at `path` as it was in the commit `commit_id`.
+    pub async fn get_commit_dir(
+        &self,
+        id: &str,
+        commit_id: &str,
+        path: &Path,
+    ) -> Result<Vec<CommitEntry>> {
+        let authorization = self.login().await?;
+        let url = format!(
+            "{}/api/v2.1/repos/{}/commits/{}/dir/",
+            self.server, id, commit_id
+        );
+
+        debug!("url: {}, {:?}", url, ("path", path));
+
+        let res = self
+            .send(
+                self.client
+                    .get(&url)
+                    .query(&[("path", path)])
+                    .header("Authorization", &authorization),
+            )
+            .await?
+            .error_for_status()?;
+        let body: CommitDir = self.json(res).await?;
+        Ok(body.dirent_list)
+    }
+
+    /// Everything in the trash of the library.
+    pub async fn get_trash(&self, id: &str) -> Result<Vec<TrashItem>> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api/v2.1/repos/{}/trash/", self.server, id);
+        let mut items = Vec::new();
+        let mut scan_stat = None;
+
+        loop {
+            debug!("url: {}, {:?}", url, ("scan_stat", &scan_stat));
+
+            let mut req = self
+                .client
+                .get(&url)
+                .query(&[("path", "/"), ("per_page", "100")])
+                .header("Authorization", &authorization);
+            if let Some(scan_stat) = &scan_stat {
+                req = req.query(&[("scan_stat", scan_stat)]);
+            }
+            let body: Trash = self.json(self.send(req).await?.error_for_status()?).await?;
+            items.extend(body.data);
+            match body.scan_stat {
+                Some(next) if body.more => scan_stat = Some(next),
+                _ => break,
+            }
+        }
+        Ok(items)
+    }
+
+    /// Restore `item` from the trash to where it was.
+    pub async fn restore_trash_item(&self, id: &str, item: &TrashItem) -> Result<()> {
+        let authorization = self.login().await?;
+        let kind = if item.is_dir { "dir" } else { "file" };
+        let url = format!("{}/api2/repos/{}/{}/revert/", self.server, id, kind);
+        let path = item.path();
+
+        debug!(
+            "url: {}, {:?}, {:?}",
+            url,
+            ("p", &path),
+            ("commit_id", &item.commit_id)
+        );
+
+        self.send(
+            self.client
+                .put(&url)
+                .form(&[
+                    ("p", path.to_string_lossy().as_ref()),
+                    ("commit_id", &item.commit_id),
+                ])
+                .header("Authorization", &authorization),
+        )
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    /// Rename the file or directory at `path` to `new_name`, within the same directory.
+    pub async fn rename(&self, id: &str, path: &Path, is_dir: bool, new_name: &str) -> Result<()> {
+        let authorization = self.login().await?;
+        let kind = if is_dir { "dir" } else { "file" };
+        let url = format!("{}/api2/repos/{}/{}/", self.server, id, kind);
+
+        debug!(
+            "url: {}, {:?}, {:?}",
+            url,
+            ("p", path),
+            ("newname", new_name)
+        );
+
+        self.send(
+            self.client
+                .post(&url)
+                .query(&[("p", path)])
+                .form(&[("operation", "rename"), ("newname", new_name)])
+                .header("Authorization", &authorization),
+        )
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    /// Move the entry `name` in `parent_dir` into the directory `dst_dir` of the same library.
+    pub async fn move_entry(
+        &self,
+        id: &str,
+        parent_dir: &Path,
+        name: &str,
+        dst_dir: &Path,
+    ) -> Result<()> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/{}/fileops/move/", self.server, id);
+
+        debug!(
+            "url: {}, {:?}, {:?}, {:?}",
+            url,
+            ("p", parent_dir),
+            ("file_names", name),
+            ("dst_dir", dst_dir)
+        );
+
+        self.send(
+            self.client
+                .post(&url)
+                .query(&[("p", parent_dir)])
+                .form(&[
+                    ("file_names", name),
+                    ("dst_repo", id),
+                    ("dst_dir", dst_dir.to_string_lossy().as_ref()),
+                ])
+                .header("Authorization", &authorization),
+        )
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    /// Share links in the library, or only those for the file or directory at `path`.
+    /// Directories are shared as paths ending in `/`.
+    pub async fn get_share_links(&self, id: &str, path: Option<&Path>) -> Result<Vec<ShareLink>> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api/v2.1/share-links/", self.server);
+
+        debug!("url: {}, {:?}, {:?}", url, ("repo_id", id), ("path", path));
+
+        let mut req = self
+            .client
+            .get(&url)
+            .query(&[("repo_id", id)])
+            .header("Authorization", &authorization);
+        if let Some(path) = path {
+            req = req.query(&[("path", path)]);
+        }
+        let res = self.send(req).await?.error_for_status()?;
+        self.json(res).await
+    }
+
+    /// Share the file or directory at `path`, optionally protected by `password` and expiring
+    /// after `expire_days`.
+    pub async fn create_share_link(
+        &self,
+        id: &str,
+        path: &Path,
+        password: Option<&str>,
+        expire_days: Option<u32>,
+    ) -> Result<ShareLink> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api/v2.1/share-links/", self.server);
+
+        debug!("url: {}, {:?}, {:?}", url, ("repo_id", id), ("path", path));
+
+        let mut request = serde_json::json!({ "repo_id": id, "path": path });
+        if let Some(password) = password {
+            request["password"] = password.into();
+        }
+        if let Some(expire_days) = expire_days {
+            request["expire_days"] = expire_days.into();
+        }
+        let res = self
+            .send(
+                self.client
+                    .post(&url)
+                    .json(&request)
+                    .header("Authorization", &authorization),
+            )
+            .await?
+            .error_for_status()?;
+        self.json(res).await
+    }
+
+    pub async fn delete_share_link(&self, token: &str) -> Result<()> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api/v2.1/share-links/{}/", self.server, token);
+
+        debug!("url: {}", url);
+
+        self.send(
+            self.client
+                .delete(&url)
+                .header("Authorization", &authorization),
+        )
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    /// Upload links in the library, or only those for the directory at `path`.
+    pub async fn get_upload_links(&self, id: &str, path: Option<&Path>) -> Result<Vec<UploadLink>> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api/v2.1/upload-links/", self.server);
+
+        debug!("url: {}, {:?}, {:?}", url, ("repo_id", id), ("path", path));
+
+        let mut req = self
+            .client
+            .get(&url)
+            .query(&[("repo_id", id)])
+            .header("Authorization", &authorization);
+        if let Some(path) = path {
+            req = req.query(&[("path", path)]);
+        }
+        let res = self.send(req).await?.error_for_status()?;
+        self.json(res).await
+    }
+
+    /// Let anyone with the link upload into the directory at `path`, optionally protected by
+    /// `password` and expiring after `expire_days`.
+    pub async fn create_upload_link(
+        &self,
+        id: &str,
+        path: &Path,
+        password: Option<&str>,
+        expire_days: Option<u32>,
+    ) -> Result<UploadLink> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api/v2.1/upload-links/", self.server);
+
+        debug!("url: {}, {:?}, {:?}", url, ("repo_id", id), ("path", path));
+
+        let mut request = serde_json::json!({ "repo_id": id, "path": path });
+        if let Some(password) = password {
+            request["password"] = password.into();
+        }
+        if let Some(expire_days) = expire_days {
+            request["expire_days"] = expire_days.into();
+        }
+        let res = self
+            .send(
+                self.client
+                    .post(&url)
+                    .json(&request)
+                    .header("Authorization", &authorization),
+            )
+            .await?
+            .error_for_status()?;
+        self.json(res).await
+    }
+
+    pub async fn delete_upload_link(&self, token: &str) -> Result<()> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api/v2.1/upload-links/{}/", self.server, token);
+
+        debug!("url: {}", url);
+
+        self.send(
+            self.client
+                .delete(&url)
+                .header("Authorization", &authorization),
+        )
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    /// Start downloading `uri`, to read the body from with `chunk` as it arrives.
+    pub async fn download_stream(&self, uri: &str) -> Result<Response> {
+        Ok(self.send(self.client.get(uri)).await?.error_for_status()?)
+    }
+
+    pub async fn download(&self, uri: &str) -> Result<Bytes> {
+        let res = self.download_stream(uri).await?;
+        self.bytes(res).await
+    }
+
+    /// Download `size` bytes starting at `offset`, or fewer at the end of the file.
+    pub async fn download_range(&self, uri: &str, offset: u64, size: u64) -> Result<Bytes> {
+        debug!("download_range: {} {} {}", uri, offset, size);
+        let range = format!("bytes={}-{}", offset, offset + size - 1);
+        let res = self
+            .send(self.client.get(uri).header(reqwest::header::RANGE, range))
+            .await?;
+        if res.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(Bytes::new());
+        }
+        let res = res.error_for_status()?;
+        let partial = res.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        let mut body = self.bytes(res).await?;
+        // A server ignoring the range sends the whole file
+        if !partial {
+            body = body.slice((offset as usize).min(body.len())..);
+            body.truncate(size as usize);
+        }
+        Ok(body)
+    }
+
+    pub async fn get_upload_link(&self, id: &str, parent_dir: &Path) -> Result<String> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/{}/upload-link/", self.server, id);
+
+        debug!("url: {}, {:?}", url, ("p", parent_dir));
+
+        let res = self
+            .send(
+                self.client
+                    .get(&url)
+                    .query(&[("p", parent_dir)])
+                    .header("Authorization", &authorization),
+            )
+            .await?
+            .error_for_status()?;
+        self.json(res).await
+    }
+
+    pub async fn get_update_link(&self, id: &str) -> Result<String> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api2/repos/{}/update-link/", self.server, id);
+
+        debug!("url: {}", url);
+
+        let res = self
+            .send(
+                self.client
+                    .get(&url)
+                    .query(&[("p", "/")])
+                    .header("Authorization", &authorization),
+            )
+            .await?
+            .error_for_status()?;
+        self.json(res).await
+    }
+
+    /// Upload `content` as `name` into `parent_dir`, using a link from `get_upload_link`, and
+    /// return the new file id.
+    ///
+    /// `relative_path`, within `parent_dir`, is created if missing. An existing file is
+    /// replaced if `replace`, otherwise the server picks a new name for the upload.
+    pub async fn upload_file(
+        &self,
+        upload_link: &str,
+        parent_dir: &Path,
+        relative_path: Option<&Path>,
+        name: &str,
+        content: Bytes,
+        replace: bool,
+    ) -> Result<String> {
+        let authorization = self.login().await?;
+
+        debug!(
+            "upload: {} {:?} {:?} {} ({} bytes)",
+            upload_link,
+            parent_dir,
+            relative_path,
+            name,
+            content.len()
+        );
+
+        let mut form = Form::new()
+            .text("parent_dir", parent_dir.to_string_lossy().into_owned())
+            .part(
+                "file",
+                Part::bytes(content.to_vec()).file_name(name.to_string()),
+            );
+        if let Some(relative_path) = relative_path {
+            form = form.text(
+                "relative_path",
+                relative_path.to_string_lossy().into_owned(),
+            );
+        }
+        if replace {
+            form = form.text("replace", "1");
+        }
+        let res = self
+            .send(
+                self.client
+                    .post(upload_link)
+                    .query(&[("ret-json", 1)])
+                    .multipart(form)
+                    .header("Authorization", &authorization),
+            )
+            .await?
+            .error_for_status()?;
+
+        let body: Vec<UploadedFile> = self.json(res).await?;
+        Ok(body.into_iter().next().ok_or("upload returned no file")?.id)
+    }
+
+    /// Replace the contents of `target_file` with `content`, using a link from
+    /// `get_update_link`, and return the new file id.
+    pub async fn update_file(
+        &self,
+        update_link: &str,
+        target_file: &Path,
+        content: Bytes,
+    ) -> Result<String> {
+        let authorization = self.login().await?;
+
+        debug!(
+            "update: {} {:?} ({} bytes)",
+            update_link,
+            target_file,
+            content.len()
+        );
+
+        let name = target_file
+            .file_name()
+            .ok_or("no file name to update")?
+            .to_string_lossy()
+            .into_owned();
+        let form = Form::new()
+            .text("target_file", target_file.to_string_lossy().into_owned())
+            .part("file", Part::bytes(content.to_vec()).file_name(name));
+        let res = self
+            .send(
+                self.client
+                    .post(update_link)
+                    .multipart(form)
+                    .header("Authorization", &authorization),
+            )
+            .await?
+            .error_for_status()?;
+
+        let body: String = self.text(res).await?;
+        Ok(body.trim_matches('"').to_string())
+    }
+
+    /// Bytes of `name` in `parent_dir` the server already holds from an interrupted chunked
+    /// upload.
+    pub async fn get_uploaded_bytes(&self, id: &str, parent_dir: &Path, name: &str) -> Result<u64> {
+        let authorization = self.login().await?;
+        let url = format!("{}/api/v2.1/repos/{}/file-uploaded-bytes/", self.server, id);
+
+        debug!("url: {}, {:?}, {:?}", url, parent_dir, name);
+
+        let res = self
+            .send(
+                self.client
+                    .get(&url)
+                    .query(&[
+                        ("parent_dir", &*parent_dir.to_string_lossy()),
+                        ("file_name", name),
+                    ])
+                    .header("Authorization", &authorization),
+            )
+            .await?
+            .error_for_status()?;
+        let body: UploadedBytes = self.json(res).await?;
+        Ok(body.uploaded_bytes)
+    }
+
+    /// Upload `size` bytes from `source` as `name` into `parent_dir`, in chunks of
+    /// `UPLOAD_CHUNK_SIZE`, and return the new file id.
+    ///
+    /// A chunk which fails is retried from the offset the server confirms, with a fresh upload
+    /// link, so only unconfirmed bytes are sent again. Only what this upload sent is resumed
+    /// from: the upload starts at 0 whatever the server holds of earlier ones, which may have
+    /// been of other contents.
+    pub async fn upload_file_chunked<R: AsyncRead + AsyncSeek + Unpin>(
+        &self,
+        id: &str,
+        parent_dir: &Path,
+        name: &str,
+        source: &mut R,
+        size: u64,
+        replace: bool,
+    ) -> Result<String> {
+        if size == 0 {
+            let link = self.get_upload_link(id, parent_dir).await?;
+            return self
+                .upload_file(&link, parent_dir, None, name, Bytes::new(), replace)
+                .await;
+        }
+        debug!("upload_chunked: {:?} {} of {}", parent_dir, name, size);
+        let mut offset = 0;
+        // End of the chunks this upload had accepted
+        let mut accepted = 0;
+        let mut link = self.get_upload_link(id, parent_dir).await?;
+        let mut attempt = 0;
+        loop {
+            let end = (offset + UPLOAD_CHUNK_SIZE).min(size);
+            let result = match read_chunk(source, offset, end).await {
+                Ok(chunk) => {
+                    let mut form =
+                        Form::new().text("parent_dir", parent_dir.to_string_lossy().into_owned());
+                    if replace {
+                        form = form.text("replace", "1");
+                    }
+                    let range = format!("bytes {}-{}/{}", offset, end - 1, size);
+                    self.upload_chunk(&link, form, name, chunk, range).await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(res) if end >= size => {
+                    let body: Vec<UploadedFile> = self.json(res).await?;
+                    return Ok(body.into_iter().next().ok_or("upload returned no file")?.id);
+                }
+                Ok(_) => {
+                    offset = end;
+                    accepted = end;
+                    attempt = 0;
+                }
+                Err(e) if attempt + 1 < UPLOAD_ATTEMPTS => {
+                    attempt += 1;
+                    warn!("upload of {} failed at {}: {}, retrying", name, offset, e);
+                    tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                    let resume = match self.get_upload_link(id, parent_dir).await {
+                        Ok(link) => self
+                            .get_uploaded_bytes(id, parent_dir, name)
+                            .await
+                            .map(|confirmed| (link, confirmed)),
+                        Err(e) => Err(e),
+                    };
+                    match resume {
+                        Ok((l, confirmed)) => {
+                            link = l;
+                            offset = confirmed.min(accepted);
+                        }
+                        Err(e) => warn!("cannot resume upload of {}: {}", name, e),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn upload_chunk(
+        &self,
+        upload_link: &str,
+        form: Form,
+        name: &str,
+        chunk: Vec<u8>,
+        range: String,
+    ) -> Result<Response> {
+        let authorization = self.login().await?;
+        let disposition = format!("attachment; filename=\"{}\"", name.replace('"', "\\\""));
+
+        debug!("upload_chunk: {} {} {}", upload_link, name, range);
+
+        let form = form.part("file", Part::bytes(chunk).file_name(name.to_string()));
+        let res = self
+            .send(
+                self.client
+                    .post(upload_link)
+                    .query(&[("ret-json", 1)])
+                    .multipart(form)
+                    .header("Authorization", &authorization)
+                    .header(reqwest::header::CONTENT_RANGE, range)
+                    .header(
+                        reqwest::header::CONTENT_DISPOSITION,
+                        reqwest::header::HeaderValue::from_bytes(disposition.as_bytes())?,
+                    ),
+            )
+            .await?
+            .error_for_status()?;
+        Ok(res)
+    }
+}
+
+/// The bytes `offset..end` of `source`.
+async fn read_chunk<R: AsyncRead + AsyncSeek + Unpin>(
+    source: &mut R,
+    offset: u64,
+    end: u64,
+) -> Result<Vec<u8>> {
+    source.seek(SeekFrom::Start(offset)).await?;
+    let mut chunk = Vec::with_capacity((end - offset) as usize);
+    source.take(end - offset).read_to_end(&mut chunk).await?;
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn read_chunk_reads_the_range() {
+        let mut source = Cursor::new((0..=255).collect::<Vec<u8>>());
+        assert_eq!(
+            read_chunk(&mut source, 10, 14).await.unwrap(),
+            vec![10, 11, 12, 13]
+        );
+        assert_eq!(read_chunk(&mut source, 0, 2).await.unwrap(), vec![0, 1]);
+        assert_eq!(read_chunk(&mut source, 250, 256).await.unwrap().len(), 6);
+    }
+}
@@ -0,0 +1,202 @@
+//! Extended attributes exposing Seafile metadata, under `user.seafile.`.
+//!
+//! Setting `user.seafile.create_share_link` creates a share link, which `user.seafile.share_link`
+//! then holds. Its value may set `password=` and `expire_days=`, comma separated.
+//!
+//! `user.seafile.pinned` is `1` on pinned files and directories and everything in them; setting
+//! it to `1` or `0` pins or unpins.
+
+use crate::resolve::{errno, Location};
+use crate::seafileapi::{LibraryEntry, ShareLink};
+use crate::SeafileFS;
+use libc::{c_int, EINVAL, EIO, ENODATA, ENOTSUP, EPERM};
+use log::debug;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const FILE_ID: &str = "user.seafile.file_id";
+pub const LIBRARY_ID: &str = "user.seafile.library_id";
+pub const PERMISSION: &str = "user.seafile.permission";
+pub const MODIFIER: &str = "user.seafile.modifier";
+pub const SHARE_LINK: &str = "user.seafile.share_link";
+pub const PATH: &str = "user.seafile.path";
+pub const CREATE_SHARE_LINK: &str = "user.seafile.create_share_link";
+pub const PINNED: &str = "user.seafile.pinned";
+
+/// The password and expiry in days from the value of `user.seafile.create_share_link`.
+fn share_options(value: &[u8]) -> Result<(Option<String>, Option<u32>), c_int> {
+    let value = std::str::from_utf8(value).map_err(|_| EINVAL)?;
+    let mut password = None;
+    let mut expire_days = None;
+    for option in value.trim().split(',').filter(|o| !o.is_empty()) {
+        match option.split_once('=') {
+            Some(("password", value)) => password = Some(value.to_string()),
+            Some(("expire_days", value)) => expire_days = Some(value.parse().map_err(|_| EINVAL)?),
+            _ => return Err(EINVAL),
+        }
+    }
+    Ok((password, expire_days))
+}
+
+/// Path of `entry` at `path` as Seafile records it for share links: directories end in `/`.
+pub fn share_path(path: &Path, entry: &LibraryEntry) -> PathBuf {
+    match entry.entry_type.as_str() {
+        "dir" => path.join(""),
+        _ => path.to_path_buf(),
+    }
+}
+
+impl SeafileFS {
+    /// The extended attributes of `location`, with their values. Only attributes with a value
+    /// are included; the share link is looked up only if `share_link`.
+    pub(crate) fn attributes(
+        &self,
+        location: Location,
+        share_link: bool,
+    ) -> Result<Vec<(&'static str, String)>, c_int> {
+        let mut attributes = Vec::new();
+        match location {
+            Location::Root => {}
+            Location::Library(library) => {
+                attributes.push((LIBRARY_ID, library.id.clone()));
+                attributes.push((PATH, "/".to_string()));
+                if self.pins.is_pinned(&library.id, Path::new("/")) {
+                    attributes.push((PINNED, "1".to_string()));
+                }
+                attributes.push((PERMISSION, library.permission));
+            }
+            Location::Entry { library, path } => {
+                let entry = self.stat(&library, &path)?;
+                let modifier = match entry.modifier_email.is_empty() {
+                    true => entry.modifier_name.clone(),
+                    false => entry.modifier_email.clone(),
+                };
+                attributes.push((FILE_ID, entry.id.clone()));
+                attributes.push((LIBRARY_ID, library.id.clone()));
+                attributes.push((PATH, path.to_string_lossy().into_owned()));
+                attributes.push((PERMISSION, entry.permission.clone()));
+                if !modifier.is_empty() {
+                    attributes.push((MODIFIER, modifier));
+                }
+                if self.pins.is_pinned(&library.id, &path) {
+                    attributes.push((PINNED, "1".to_string()));
+                }
+                if share_link {
+                    let shared = share_path(&path, &entry);
+                    let links = self.share_links(&library.id)?;
+                    if let Some(link) = links
+                        .iter()
+                        .find(|link| !link.is_expired && Path::new(&link.path) == shared)
+                    {
+                        attributes.push((SHARE_LINK, link.link.clone()));
+                    }
+                }
+            }
+            Location::Revision {
+                library, revision, ..
+            } => {
+                attributes.push((FILE_ID, revision.rev_file_id));
+                attributes.push((LIBRARY_ID, library.id));
+                if !revision.creator_name.is_empty() {
+                    attributes.push((MODIFIER, revision.creator_name));
+                }
+            }
+            Location::Snapshot { library, entry, .. } => {
+                if let Some(entry) = entry {
+                    attributes.push((FILE_ID, entry.obj_id));
+                }
+                attributes.push((LIBRARY_ID, library.id));
+            }
+            Location::History { library, .. }
+            | Location::FileHistory { library, .. }
+            | Location::Snapshots(library)
+            | Location::Trash(library) => {
+                attributes.push((LIBRARY_ID, library.id));
+            }
+        }
+        Ok(attributes)
+    }
+
+    /// The value of the extended attribute `name` of `location`.
+    pub(crate) fn attribute(&self, location: Location, name: &str) -> Result<String, c_int> {
+        self.attributes(location, name == SHARE_LINK)?
+            .into_iter()
+            .find(|(attribute, _)| *attribute == name)
+            .map(|(_, value)| value)
+            .ok_or(ENODATA)
+    }
+
+    /// Set the extended attribute `name` of `location`. Only `user.seafile.create_share_link`
+    /// can be set, on entries within a library, and `user.seafile.pinned`, on those and on
+    /// libraries.
+    pub(crate) fn set_attribute(
+        &self,
+        location: Location,
+        name: &str,
+        value: &[u8],
+    ) -> Result<(), c_int> {
+        if name == PINNED {
+            let (library, path) = match location {
+                Location::Library(library) => (library, PathBuf::from("/")),
+                Location::Entry { library, path } => (library, path),
+                _ => return Err(EPERM),
+            };
+            return self.set_pinned(&library.id, &path, value);
+        }
+        if name != CREATE_SHARE_LINK {
+            return Err(ENOTSUP);
+        }
+        let (library, path) = match location {
+            Location::Entry { library, path } => (library, path),
+            _ => return Err(EPERM),
+        };
+        let (password, expire_days) = share_options(value)?;
+        let entry = self.stat(&library, &path)?;
+        let link = self
+            .api
+            .create_share_link(
+                &library.id,
+                &share_path(&path, &entry),
+                password.as_deref(),
+                expire_days,
+            )
+            .map_err(|e| {
+                debug!("ERROR: create_share_link({:?}) {}", path, e);
+                errno(&e)
+            })?;
+        debug!("shared {:?} as {}", path, link.link);
+        self.share_links.remove(&library.id);
+        Ok(())
+    }
+
+    /// The share links of the library `library_id`, through the metadata cache.
+    fn share_links(&self, library_id: &str) -> Result<Arc<Vec<ShareLink>>, c_int> {
+        if let Some(links) = self.share_links.get(&library_id.to_string()) {
+            return Ok(links);
+        }
+        let links = self.api.get_share_links(library_id, None).map_err(|e| {
+            debug!("ERROR: get_share_links({:?}) {}", library_id, e);
+            errno(&e)
+        })?;
+        let links = Arc::new(links);
+        self.share_links
+            .insert(library_id.to_string(), links.clone());
+        Ok(links)
+    }
+
+    /// Pin or unpin `path`, as `value` is `1` or `0`, and fetch or drop it in the background.
+    fn set_pinned(&self, library_id: &str, path: &Path, value: &[u8]) -> Result<(), c_int> {
+        let result = match value.trim_ascii() {
+            b"1" => self.pins.pin(library_id, path),
+            b"0" => self.pins.unpin(library_id, path),
+            _ => return Err(EINVAL),
+        };
+        let changed = result.map_err(|e| e.raw_os_error().unwrap_or(EIO))?;
+        debug!("pinned {:?}: {:?} (changed: {})", path, value, changed);
+        if changed {
+            self.pins
+                .spawn_sync(self.api.clone(), vec![library_id.to_string()]);
+        }
+        Ok(())
+    }
+}
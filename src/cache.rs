@@ -0,0 +1,298 @@
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::ops::RangeInclusive;
+use std::sync::{Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Size of the blocks file contents are fetched and cached in.
+pub const BLOCK_SIZE: u64 = 1 << 20;
+
+/// A map whose entries expire `ttl` after insertion.
+///
+/// Expired entries are kept until replaced or removed, to fall back on while the server is
+/// unreachable. Locks are only held while the map itself is accessed, never while a value is being
+/// fetched, so concurrent misses for the same key may both fetch it.
+#[derive(Debug)]
+pub struct TtlCache<K, V> {
+    entries: RwLock<HashMap<K, (Instant, V)>>,
+    ttl: Duration,
+}
+
+impl<K: Eq + Hash, V: Clone> TtlCache<K, V> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.read().unwrap();
+        match entries.get(key) {
+            Some((inserted, value)) if inserted.elapsed() < self.ttl => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.write().unwrap();
+        entries.insert(key, (Instant::now(), value));
+    }
+
+    /// Expire the entry for `key` now, keeping it to fall back on.
+    pub fn expire(&self, key: &K) {
+        let mut entries = self.entries.write().unwrap();
+        match Instant::now().checked_sub(self.ttl) {
+            Some(expired) => {
+                if let Some((inserted, _)) = entries.get_mut(key) {
+                    *inserted = expired;
+                }
+            }
+            None => {
+                entries.remove(key);
+            }
+        }
+    }
+
+    /// The entry for `key`, even if expired.
+    pub fn get_stale(&self, key: &K) -> Option<V> {
+        let entries = self.entries.read().unwrap();
+        entries.get(key).map(|(_, value)| value.clone())
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.entries.write().unwrap().remove(key);
+    }
+
+    /// Drop every entry whose key does not satisfy `keep`.
+    pub fn retain(&self, mut keep: impl FnMut(&K) -> bool) {
+        self.entries.write().unwrap().retain(|key, _| keep(key));
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> TtlCache<K, V> {
+    /// Keys of the entries not yet expired.
+    pub fn keys(&self) -> Vec<K> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .iter()
+            .filter(|(_, (inserted, _))| inserted.elapsed() < self.ttl)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+}
+
+/// File contents in blocks of `BLOCK_SIZE`, keyed by Seafile file id and block index.
+///
+/// File ids change with the contents, so blocks never go stale; the oldest are dropped once
+/// `capacity` bytes are held.
+#[derive(Debug)]
+pub struct BlockCache {
+    inner: RwLock<Blocks>,
+    capacity: u64,
+    /// Blocks being downloaded, so readers wait for them rather than download them again
+    fetching: Mutex<HashSet<(String, u64)>>,
+    fetched: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Blocks {
+    blocks: HashMap<(String, u64), Bytes>,
+    order: VecDeque<(String, u64)>,
+    size: u64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            inner: RwLock::new(Blocks::default()),
+            capacity,
+            fetching: Mutex::new(HashSet::new()),
+            fetched: Condvar::new(),
+        }
+    }
+
+    /// Blocks `blocks` of `id`, those the cache has, and a guard marking the span from the
+    /// first to the last missing one as being fetched by the caller until it is dropped.
+    ///
+    /// The blocks are looked up and marked at once, so no two callers fetch the same block.
+    /// Blocks others are fetching are waited for if `wait`; otherwise they are left to them,
+    /// and do not count as missing.
+    pub fn claim(
+        &self,
+        id: &str,
+        blocks: RangeInclusive<u64>,
+        wait: bool,
+    ) -> (Vec<Option<Bytes>>, Option<Fetching<'_>>) {
+        let first = *blocks.start();
+        let keys = blocks
+            .map(|block| (id.to_string(), block))
+            .collect::<Vec<_>>();
+        let mut fetching = self.fetching.lock().unwrap();
+        while wait && keys.iter().any(|key| fetching.contains(key)) {
+            fetching = self.fetched.wait(fetching).unwrap();
+        }
+        let found = {
+            let inner = self.inner.read().unwrap();
+            keys.iter()
+                .map(|key| inner.blocks.get(key).cloned())
+                .collect::<Vec<_>>()
+        };
+        let missing = |i: &usize| found[*i].is_none() && !fetching.contains(&keys[*i]);
+        let span = (0..keys.len())
+            .find(missing)
+            .zip((0..keys.len()).rfind(missing));
+        let guard = span.map(|(missing_first, missing_last)| {
+            let claimed = keys[missing_first..=missing_last]
+                .iter()
+                .filter(|key| !fetching.contains(*key))
+                .cloned()
+                .collect::<Vec<_>>();
+            fetching.extend(claimed.iter().cloned());
+            Fetching {
+                cache: self,
+                blocks: first + missing_first as u64..=first + missing_last as u64,
+                keys: claimed,
+            }
+        });
+        (found, guard)
+    }
+
+    pub fn get(&self, id: &str, block: u64) -> Option<Bytes> {
+        let inner = self.inner.read().unwrap();
+        inner.blocks.get(&(id.to_string(), block)).cloned()
+    }
+
+    pub fn insert(&self, id: &str, block: u64, data: Bytes) {
+        let mut inner = self.inner.write().unwrap();
+        let key = (id.to_string(), block);
+        inner.size += data.len() as u64;
+        if let Some(old) = inner.blocks.insert(key.clone(), data) {
+            inner.size -= old.len() as u64;
+        } else {
+            inner.order.push_back(key);
+        }
+        while inner.size > self.capacity {
+            let oldest = match inner.order.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some(old) = inner.blocks.remove(&oldest) {
+                inner.size -= old.len() as u64;
+            }
+        }
+    }
+}
+
+/// Blocks being fetched, waited for by `BlockCache::claim` until dropped.
+pub struct Fetching<'a> {
+    cache: &'a BlockCache,
+    /// The span to fetch, which may take in blocks the cache has or others are fetching
+    pub blocks: RangeInclusive<u64>,
+    /// The blocks marked as being fetched
+    keys: Vec<(String, u64)>,
+}
+
+impl Drop for Fetching<'_> {
+    fn drop(&mut self) {
+        let mut fetching = self.cache.fetching.lock().unwrap();
+        for key in &self.keys {
+            fetching.remove(key);
+        }
+        self.cache.fetched.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn block(byte: u8) -> Bytes {
+        Bytes::from(vec![byte; 4])
+    }
+
+    #[test]
+    fn ttl_cache_expires_but_keeps_stale_entries() {
+        let cache = TtlCache::new(Duration::from_secs(60));
+        cache.insert("a", 1);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.keys(), vec!["a"]);
+        cache.expire(&"a");
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get_stale(&"a"), Some(1));
+        assert!(cache.keys().is_empty());
+        cache.remove(&"a");
+        assert_eq!(cache.get_stale(&"a"), None);
+    }
+
+    #[test]
+    fn block_cache_drops_oldest_over_capacity() {
+        let cache = BlockCache::new(8);
+        cache.insert("f", 0, block(0));
+        cache.insert("f", 1, block(1));
+        cache.insert("f", 1, block(2));
+        assert_eq!(cache.get("f", 0), Some(block(0)));
+        cache.insert("f", 2, block(3));
+        assert_eq!(cache.get("f", 0), None);
+        assert_eq!(cache.get("f", 1), Some(block(2)));
+        assert_eq!(cache.get("f", 2), Some(block(3)));
+    }
+
+    #[test]
+    fn claim_spans_first_to_last_missing_block() {
+        let cache = BlockCache::new(1 << 20);
+        cache.insert("f", 0, block(0));
+        cache.insert("f", 2, block(2));
+        let (found, fetching) = cache.claim("f", 0..=4, true);
+        assert_eq!(
+            found,
+            vec![Some(block(0)), None, Some(block(2)), None, None]
+        );
+        assert_eq!(fetching.unwrap().blocks, 1..=4);
+    }
+
+    #[test]
+    fn claim_of_cached_blocks_fetches_nothing() {
+        let cache = BlockCache::new(1 << 20);
+        cache.insert("f", 3, block(3));
+        let (found, fetching) = cache.claim("f", 3..=3, true);
+        assert_eq!(found, vec![Some(block(3))]);
+        assert!(fetching.is_none());
+    }
+
+    #[test]
+    fn claim_without_waiting_leaves_blocks_to_their_fetcher() {
+        let cache = BlockCache::new(1 << 20);
+        let (_, first) = cache.claim("f", 0..=1, false);
+        assert_eq!(first.as_ref().unwrap().blocks, 0..=1);
+        let (found, second) = cache.claim("f", 0..=3, false);
+        assert_eq!(found, vec![None; 4]);
+        let second = second.unwrap();
+        assert_eq!(second.blocks, 2..=3);
+        drop(first);
+        let (_, third) = cache.claim("f", 0..=3, false);
+        assert_eq!(third.unwrap().blocks, 0..=1);
+    }
+
+    #[test]
+    fn claim_waits_for_blocks_being_fetched() {
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let (_, fetching) = cache.claim("f", 0..=0, true);
+        let fetching = fetching.unwrap();
+        let waiter = {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                let (found, fetching) = cache.claim("f", 0..=0, true);
+                (found, fetching.is_some())
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        cache.insert("f", 0, block(0));
+        drop(fetching);
+        assert_eq!(waiter.join().unwrap(), (vec![Some(block(0))], false));
+    }
+}
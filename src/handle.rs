@@ -0,0 +1,205 @@
+//! Open file handles, and the local copies written to before uploading.
+
+use crate::seafileapi::Library;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+/// Where the file of a handle is and how big its local copy is, kept apart from the handle so
+/// they can be looked up while it is locked, as it is for the length of an upload.
+#[derive(Debug)]
+pub struct Location {
+    pub library_id: String,
+    /// Path within the library
+    path: RwLock<PathBuf>,
+    /// The local copy, once there is one, through a descriptor of its own
+    staging: OnceLock<File>,
+}
+
+impl Location {
+    pub fn path(&self) -> PathBuf {
+        self.path.read().unwrap().clone()
+    }
+
+    pub fn set_path(&self, path: PathBuf) {
+        *self.path.write().unwrap() = path;
+    }
+
+    /// Size of the local copy, if there is one.
+    pub fn staged_size(&self) -> Option<u64> {
+        let metadata = self.staging.get()?.metadata().ok()?;
+        Some(metadata.len())
+    }
+}
+
+#[derive(Debug)]
+pub struct Handle {
+    pub library: Library,
+    pub location: Arc<Location>,
+    /// Whether the handle is open for writing
+    pub writable: bool,
+    /// Local copy of the contents, made for handles open for writing as they are truncated or
+    /// first written to
+    pub staging: Option<File>,
+    /// Whether `staging` has changes not yet uploaded
+    pub dirty: bool,
+    /// Content id of the file as it was opened or last uploaded, if known; a different id on
+    /// the server means someone else changed it meanwhile
+    pub base_id: Option<String>,
+    /// Where the last read through the handle ended, to tell sequential reads apart
+    pub read_end: u64,
+    /// How far past the reads contents have been fetched ahead
+    pub read_ahead_end: u64,
+}
+
+impl Handle {
+    pub fn new(library: Library, path: PathBuf) -> Self {
+        let location = Location {
+            library_id: library.id.clone(),
+            path: RwLock::new(path),
+            staging: OnceLock::new(),
+        };
+        Self {
+            library,
+            location: Arc::new(location),
+            writable: false,
+            staging: None,
+            dirty: false,
+            base_id: None,
+            read_end: 0,
+            read_ahead_end: 0,
+        }
+    }
+
+    /// Path within the library.
+    pub fn path(&self) -> PathBuf {
+        self.location.path()
+    }
+
+    pub fn set_path(&self, path: PathBuf) {
+        self.location.set_path(path)
+    }
+
+    /// Give the handle `staging` as its local copy.
+    pub fn set_staging(&mut self, staging: File) -> std::io::Result<()> {
+        // A handle is staged once, so the location cannot have a copy already
+        let _ = self.location.staging.set(staging.try_clone()?);
+        self.staging = Some(staging);
+        Ok(())
+    }
+}
+
+type Entry = (Arc<Mutex<Handle>>, Arc<Location>);
+
+/// Handles by number. Each handle has its own lock, so work on one file (such as an upload)
+/// never blocks another, and its location is kept beside it, so looking it up never waits for
+/// such work.
+#[derive(Debug)]
+pub struct Handles {
+    handles: RwLock<HashMap<u64, Entry>>,
+    next: AtomicU64,
+}
+
+impl Handles {
+    pub fn new() -> Self {
+        Self {
+            handles: RwLock::new(HashMap::new()),
+            next: AtomicU64::new(1),
+        }
+    }
+
+    pub fn insert(&self, handle: Handle) -> u64 {
+        let fh = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut handles = self.handles.write().unwrap();
+        let location = handle.location.clone();
+        handles.insert(fh, (Arc::new(Mutex::new(handle)), location));
+        fh
+    }
+
+    pub fn get(&self, fh: u64) -> Option<Arc<Mutex<Handle>>> {
+        let handles = self.handles.read().unwrap();
+        handles.get(&fh).map(|(handle, _)| handle.clone())
+    }
+
+    /// Where the handle `fh` is open, without waiting for its lock.
+    pub fn location(&self, fh: u64) -> Option<Arc<Location>> {
+        let handles = self.handles.read().unwrap();
+        handles.get(&fh).map(|(_, location)| location.clone())
+    }
+
+    /// Numbers of the handles open on `path` in the library `library_id`.
+    pub fn find(&self, library_id: &str, path: &Path) -> Vec<u64> {
+        let handles = self.handles.read().unwrap();
+        handles
+            .iter()
+            .filter(|(_, (_, location))| {
+                location.library_id == library_id && *location.path.read().unwrap() == path
+            })
+            .map(|(fh, _)| *fh)
+            .collect()
+    }
+
+    /// Move the handles open on `from`, or anywhere under it, to `to`.
+    pub fn rename(&self, library_id: &str, from: &Path, to: &Path) {
+        let handles = self.handles.read().unwrap();
+        for (_, location) in handles.values() {
+            if location.library_id != library_id {
+                continue;
+            }
+            let mut path = location.path.write().unwrap();
+            if let Ok(rest) = path.strip_prefix(from) {
+                *path = to.join(rest);
+            }
+        }
+    }
+
+    pub fn remove(&self, fh: u64) -> Option<Arc<Mutex<Handle>>> {
+        let mut handles = self.handles.write().unwrap();
+        handles.remove(&fh).map(|(handle, _)| handle)
+    }
+}
+
+impl Default for Handles {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Library id and path of each open directory within a library, by handle number.
+#[derive(Debug)]
+pub struct DirectoryHandles {
+    handles: RwLock<HashMap<u64, (String, PathBuf)>>,
+    next: AtomicU64,
+}
+
+impl DirectoryHandles {
+    pub fn new() -> Self {
+        Self {
+            handles: RwLock::new(HashMap::new()),
+            next: AtomicU64::new(1),
+        }
+    }
+
+    pub fn insert(&self, library_id: &str, path: PathBuf) -> u64 {
+        let fh = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut handles = self.handles.write().unwrap();
+        handles.insert(fh, (library_id.to_string(), path));
+        fh
+    }
+
+    pub fn get(&self, fh: u64) -> Option<(String, PathBuf)> {
+        self.handles.read().unwrap().get(&fh).cloned()
+    }
+
+    pub fn remove(&self, fh: u64) {
+        self.handles.write().unwrap().remove(&fh);
+    }
+}
+
+impl Default for DirectoryHandles {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,89 @@
+//! Read-only `.seafile-history` directories, holding past revisions of the files beside them.
+//!
+//! `<dir>/.seafile-history/<name>/` lists the revisions of `<dir>/<name>`, each named
+//! `<timestamp>-<commit>`. The directories are not listed, but can be entered by name.
+
+use crate::resolve::{errno, Location};
+use crate::seafileapi::{FileRevision, Library};
+use crate::SeafileFS;
+use chrono::{TimeZone, Utc};
+use libc::{c_int, ENOENT};
+use log::debug;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const HISTORY_DIR: &str = ".seafile-history";
+
+/// `ctime` as used in file names, in UTC and without colons.
+pub fn timestamp(ctime: u64) -> String {
+    let time = Utc
+        .timestamp_opt(ctime as i64, 0)
+        .single()
+        .unwrap_or_default();
+    time.format("%Y-%m-%dT%H%M%SZ").to_string()
+}
+
+/// The file name of `revision`: when it was made and the start of its commit id.
+pub fn revision_name(revision: &FileRevision) -> String {
+    let commit = revision.id.get(..8).unwrap_or(&revision.id);
+    format!("{}-{}", timestamp(revision.ctime), commit)
+}
+
+impl SeafileFS {
+    /// Revisions of `file`, through the metadata cache.
+    pub(crate) fn file_history(
+        &self,
+        library_id: &str,
+        file: &Path,
+    ) -> Result<Arc<Vec<FileRevision>>, c_int> {
+        let key = (library_id.to_string(), file.to_path_buf());
+        if let Some(revisions) = self.history.get(&key) {
+            return Ok(revisions);
+        }
+        let revisions = self.api.get_file_history(library_id, file).map_err(|e| {
+            debug!("ERROR: get_file_history({:?}) {}", file, e);
+            errno(&e)
+        })?;
+        let revisions = Arc::new(revisions);
+        self.history.insert(key, revisions.clone());
+        Ok(revisions)
+    }
+
+    /// Resolve the components `rest` following `<dir>/.seafile-history`.
+    pub(crate) fn resolve_history(
+        &self,
+        library: Library,
+        dir: PathBuf,
+        rest: &[&OsStr],
+    ) -> Result<Location, c_int> {
+        if dir.parent().is_some() {
+            self.stat_dir(&library, &dir)?;
+        }
+        let file = match rest {
+            [] => return Ok(Location::History { library, dir }),
+            [name, ..] => dir.join(name),
+        };
+        if self.stat(&library, &file)?.entry_type == "dir" {
+            return Err(ENOENT);
+        }
+        match rest {
+            [_] => Ok(Location::FileHistory { library, file }),
+            [_, name] => {
+                let name = name.to_string_lossy();
+                let revision = self
+                    .file_history(&library.id, &file)?
+                    .iter()
+                    .find(|revision| revision_name(revision) == name)
+                    .cloned()
+                    .ok_or(ENOENT)?;
+                Ok(Location::Revision {
+                    library,
+                    file,
+                    revision,
+                })
+            }
+            _ => Err(ENOENT),
+        }
+    }
+}
@@ -0,0 +1,1280 @@
+//! The write-back journal: changes to make on the server, recorded on disk first so that none
+//! are lost to a crash or to the server being unreachable.
+//!
+//! Each operation is a file `<id>.json` in the journal directory, with the contents to upload,
+//! if any, beside it in `<id>.data`. Operations on the same paths, or one inside the other, are
+//! made in order of id; whoever makes one, a mount or `journal-replay`, claims it with an
+//! exclusive `flock` on its record. Those that cannot be made straight away, because earlier
+//! ones are still to be made or the server is unreachable, stay in the journal and are replayed
+//! as soon as they can be, or on the next mount; until then directory listings show them as
+//! done. One that the server refuses on replay is marked failed, holds up nothing, and is left
+//! until replayed with `journal-replay` or discarded; one that fails for any other reason is
+//! retried, holding up those after it on the same paths.
+//!
+//! The records are kept in memory too, read again from disk only when the directory was changed
+//! by another process.
+
+use crate::asyncapi::runtime;
+use crate::options::{state_dir, ConflictPolicy};
+use crate::resolve::errno;
+use crate::seafileapi::{self, success, LibraryEntry, SeafileAPI, UPLOAD_CHUNK_SIZE};
+use crate::writeback::conflict_copy;
+use crate::{DirectoryCache, SeafileFS};
+use bytes::Bytes;
+use chrono::Utc;
+use libc::{c_int, EIO};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+use tokio::{task, time};
+
+/// How often pending operations are retried
+const REPLAY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Start of the ids listings give entries whose contents are still in the journal
+pub const PENDING_ID: &str = "journal:";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum Operation {
+    /// Create an empty file
+    Create {
+        path: PathBuf,
+    },
+    /// Upload the recorded contents to `path`, over the file there if `replace`, else beside it
+    Upload {
+        path: PathBuf,
+        replace: bool,
+        /// Id of the contents the upload replaces, if known, to tell on replay whether someone
+        /// else changed the file meanwhile
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        base_id: Option<String>,
+    },
+    Mkdir {
+        path: PathBuf,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+        is_dir: bool,
+    },
+    Delete {
+        path: PathBuf,
+        is_dir: bool,
+    },
+}
+
+impl Operation {
+    /// The paths the operation changes.
+    fn paths(&self) -> Vec<&Path> {
+        match self {
+            Operation::Create { path }
+            | Operation::Upload { path, .. }
+            | Operation::Mkdir { path }
+            | Operation::Delete { path, .. } => vec![path],
+            Operation::Rename { from, to, .. } => vec![from, to],
+        }
+    }
+}
+
+/// An operation in the journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub id: u64,
+    pub library_id: String,
+    #[serde(flatten)]
+    pub operation: Operation,
+    /// When the operation was recorded, in seconds since the epoch
+    pub queued: i64,
+    /// Failed replays
+    #[serde(default)]
+    pub attempts: u32,
+    /// Why the last replay failed
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Refused by the server, so only made again when asked with `journal-replay`
+    #[serde(default)]
+    pub failed: bool,
+}
+
+impl Record {
+    /// Whether `later`, recorded after this, is to be made after it: they are in the same
+    /// library, and change the same paths, or one inside the other.
+    fn holds_up(&self, later: &Record) -> bool {
+        self.library_id == later.library_id
+            && self.operation.paths().iter().any(|path| {
+                later
+                    .operation
+                    .paths()
+                    .iter()
+                    .any(|other| path.starts_with(other) || other.starts_with(path))
+            })
+    }
+}
+
+/// Whether the server refused an operation, and so will again.
+fn refused(e: &seafileapi::Error) -> bool {
+    match e.downcast_ref::<reqwest::Error>() {
+        Some(e) => e.status().is_some_and(|status| status.is_client_error()),
+        // Not the server's doing: missing contents, or a conflict it was not to be made over
+        None => true,
+    }
+}
+
+/// The entry at `path` on the server, if there is one.
+fn find(
+    api: &SeafileAPI,
+    library_id: &str,
+    path: &Path,
+) -> seafileapi::Result<Option<LibraryEntry>> {
+    let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    match api.get_library_content(library_id, parent) {
+        Ok(entries) => Ok(entries.into_iter().find(|entry| entry.name == name)),
+        Err(e) if errno(&e) == libc::ENOENT => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `path` exists on the server.
+fn exists(api: &SeafileAPI, library_id: &str, path: &Path) -> seafileapi::Result<bool> {
+    Ok(find(api, library_id, path)?.is_some())
+}
+
+/// Where `dir` was before the operations `records` (oldest first) renamed it, or `None` if
+/// it was not there yet.
+fn before<'a>(dir: &Path, records: impl DoubleEndedIterator<Item = &'a Record>) -> Option<PathBuf> {
+    let mut dir = dir.to_path_buf();
+    for record in records.rev() {
+        match &record.operation {
+            Operation::Rename {
+                from,
+                to,
+                is_dir: true,
+            } => {
+                if let Ok(rest) = dir.strip_prefix(to) {
+                    dir = match rest.as_os_str().is_empty() {
+                        true => from.clone(),
+                        false => from.join(rest),
+                    };
+                } else if dir.starts_with(from) {
+                    // Whatever is there now came after the rename
+                    return None;
+                }
+            }
+            Operation::Mkdir { path } if dir.starts_with(path) => return None,
+            _ => {}
+        }
+    }
+    Some(dir)
+}
+
+/// `file` locked with `flock(operation)`, or `None` if `LOCK_NB` was asked for and the lock is
+/// held elsewhere. Closing the file unlocks it.
+fn flock(file: File, operation: c_int) -> io::Result<Option<File>> {
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        return Ok(Some(file));
+    }
+    match io::Error::last_os_error() {
+        e if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        e => Err(e),
+    }
+}
+
+fn modified(path: &Path) -> io::Result<SystemTime> {
+    fs::metadata(path)?.modified()
+}
+
+/// The records on disk, as last read or changed.
+#[derive(Debug, Default)]
+struct Index {
+    records: BTreeMap<u64, Record>,
+    /// Modification time of the directory as of `records`, which changes made by any other
+    /// process move on
+    stamp: Option<SystemTime>,
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    dir: PathBuf,
+    index: Mutex<Index>,
+    /// Notified when operations may be ready to replay
+    wakeup: Notify,
+}
+
+impl Journal {
+    /// The journal in `dir`, which is created once needed.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            index: Mutex::new(Index::default()),
+            wakeup: Notify::new(),
+        }
+    }
+
+    /// Where the journal for `username` on `server` is kept unless told otherwise.
+    pub fn default_dir(server: &str, username: &str) -> PathBuf {
+        state_dir("journal", server, username)
+    }
+
+    fn record_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.json", id))
+    }
+
+    fn data_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.data", id))
+    }
+
+    /// Pending operations, oldest first.
+    pub fn records(&self) -> io::Result<Vec<Record>> {
+        self.with_records(|records| records.values().cloned().collect())
+    }
+
+    /// Call `f` with the pending operations, reading them from disk only if they changed there.
+    fn with_records<T>(&self, f: impl FnOnce(&BTreeMap<u64, Record>) -> T) -> io::Result<T> {
+        let mut index = self.index.lock().unwrap();
+        let stamp = match modified(&self.dir) {
+            Ok(stamp) => stamp,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(f(&BTreeMap::new())),
+            Err(e) => return Err(e),
+        };
+        if index.stamp != Some(stamp) {
+            index.records = self.read_records()?;
+            index.stamp = Some(stamp);
+        }
+        Ok(f(&index.records))
+    }
+
+    fn read_records(&self) -> io::Result<BTreeMap<u64, Record>> {
+        let mut records = BTreeMap::new();
+        for file in fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                match fs::read(&path).map(|json| serde_json::from_slice::<Record>(&json)) {
+                    Ok(Ok(record)) => {
+                        records.insert(record.id, record);
+                    }
+                    // Discarded meanwhile, or half written when the last mount crashed
+                    Ok(Err(e)) => warn!("ignoring {:?}: {}", path, e),
+                    Err(e) => debug!("ERROR: reading {:?} {}", path, e),
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Change the directory with `change`, and the records in memory to match with `update`.
+    /// Changes are made under a lock on `.lock`, so that those by other processes can be told
+    /// from these by the modification time of the directory. `change` is given the lock file,
+    /// which holds the last id given out.
+    fn change<T>(
+        &self,
+        change: impl FnOnce(&mut File) -> io::Result<T>,
+        update: impl FnOnce(&mut BTreeMap<u64, Record>, &T),
+    ) -> io::Result<T> {
+        fs::create_dir_all(&self.dir)?;
+        let lock = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(self.dir.join(".lock"))?;
+        let mut lock = flock(lock, libc::LOCK_EX)?.ok_or(io::ErrorKind::WouldBlock)?;
+        let before = modified(&self.dir)?;
+        let result = change(&mut lock)?;
+        let mut index = self.index.lock().unwrap();
+        update(&mut index.records, &result);
+        // Unless changed elsewhere since last read, in which case it is read again
+        index.stamp = match index.stamp == Some(before) {
+            true => Some(modified(&self.dir)?),
+            false => None,
+        };
+        Ok(result)
+    }
+
+    /// An id for a new record, given `lock`, the lock file held by `change`: after those of
+    /// the records on disk, and of any given out before, so that ids are not given out twice,
+    /// by any process.
+    fn next_id(&self, lock: &mut File) -> io::Result<u64> {
+        let mut last = String::new();
+        lock.read_to_string(&mut last)?;
+        let mut id = last.trim().parse::<u64>().unwrap_or(0);
+        for file in fs::read_dir(&self.dir)? {
+            let name = file?.file_name();
+            let name = name.to_string_lossy();
+            if let Ok(taken) = name.split('.').next().unwrap_or_default().parse::<u64>() {
+                id = id.max(taken);
+            }
+        }
+        id += 1;
+        lock.set_len(0)?;
+        lock.write_all_at(id.to_string().as_bytes(), 0)?;
+        Ok(id)
+    }
+
+    /// Claim the operation `id`, to make or drop it, waiting for whoever has if `wait`.
+    /// Returns `None` if someone else has it and not waiting, or it is gone. The claim is an
+    /// exclusive `flock` on the record, held until the file returned is closed.
+    fn claim(&self, id: u64, wait: bool) -> io::Result<Option<File>> {
+        let path = self.record_path(id);
+        let operation = match wait {
+            true => libc::LOCK_EX,
+            false => libc::LOCK_EX | libc::LOCK_NB,
+        };
+        loop {
+            let claim = match File::open(&path) {
+                Ok(file) => match flock(file, operation)? {
+                    Some(claim) => claim,
+                    None => return Ok(None),
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            // Unless made and removed, or saved again, by whoever had it meanwhile
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.ino() == claim.metadata()?.ino() => {
+                    return Ok(Some(claim))
+                }
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Write `record` to disk, replacing any earlier version. Returns a claim on it, taken
+    /// before it replaces the earlier version.
+    fn write(&self, record: &Record) -> io::Result<File> {
+        let path = self.record_path(record.id);
+        let temporary = path.with_extension("json.tmp");
+        let file = File::create(&temporary)?;
+        let mut file = flock(file, libc::LOCK_EX)?.ok_or(io::ErrorKind::WouldBlock)?;
+        file.write_all(&serde_json::to_vec_pretty(record)?)?;
+        file.sync_all()?;
+        fs::rename(temporary, path)?;
+        Ok(file)
+    }
+
+    /// Write `record` to disk, replacing the earlier version, which the caller has a claim on.
+    fn save(&self, record: &Record) -> io::Result<File> {
+        self.change(
+            |_| self.write(record),
+            |records, _| {
+                records.insert(record.id, record.clone());
+            },
+        )
+    }
+
+    /// Record `operation` on the library `library_id`, with `content` if it is an upload.
+    pub fn append(
+        &self,
+        library_id: &str,
+        operation: Operation,
+        content: Option<&mut File>,
+    ) -> io::Result<Record> {
+        Ok(self.append_claimed(library_id, operation, content)?.0)
+    }
+
+    /// Record `operation` like `append`, with a claim on it.
+    fn append_claimed(
+        &self,
+        library_id: &str,
+        operation: Operation,
+        content: Option<&mut File>,
+    ) -> io::Result<(Record, File)> {
+        fs::create_dir_all(&self.dir)?;
+        // Copied before taking an id, so the lock is not held meanwhile
+        let data = match content {
+            Some(content) => {
+                let mut data = tempfile::NamedTempFile::new_in(&self.dir)?;
+                content.seek(SeekFrom::Start(0))?;
+                io::copy(content, data.as_file_mut())?;
+                data.as_file().sync_all()?;
+                Some(data)
+            }
+            None => None,
+        };
+        let (record, claim) = self.change(
+            |lock| {
+                let record = Record {
+                    id: self.next_id(lock)?,
+                    library_id: library_id.to_string(),
+                    operation,
+                    queued: Utc::now().timestamp(),
+                    attempts: 0,
+                    error: None,
+                    failed: false,
+                };
+                if let Some(data) = data {
+                    data.persist(self.data_path(record.id))
+                        .map_err(|e| e.error)?;
+                }
+                let claim = self.write(&record)?;
+                Ok((record, claim))
+            },
+            |records, (record, _)| {
+                records.insert(record.id, record.clone());
+            },
+        )?;
+        debug!("journal: recorded {:?}", record);
+        Ok((record, claim))
+    }
+
+    /// Drop the operation `id` without making it, once no one is making it. Returns whether it
+    /// was there.
+    pub fn discard(&self, id: u64) -> io::Result<bool> {
+        match self.claim(id, true)? {
+            Some(_claim) => self.remove(id),
+            None => Ok(false),
+        }
+    }
+
+    /// Drop the operation `id`, once made. Returns whether it was there.
+    fn remove(&self, id: u64) -> io::Result<bool> {
+        self.change(
+            |_| {
+                let removed = match fs::remove_file(self.record_path(id)) {
+                    Ok(()) => true,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+                    Err(e) => return Err(e),
+                };
+                match fs::remove_file(self.data_path(id)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                    _ => Ok(removed),
+                }
+            },
+            |records, _| {
+                records.remove(&id);
+            },
+        )
+    }
+
+    /// Whether operations recorded before `record` that it is to be made after are still to
+    /// be made.
+    fn waits(&self, record: &Record) -> io::Result<bool> {
+        self.with_records(|records| {
+            records
+                .range(..record.id)
+                .any(|(_, earlier)| !earlier.failed && earlier.holds_up(record))
+        })
+    }
+
+    /// Make the operation `record` on the server, returning the new file id for uploads.
+    /// Replayed operations may have been made already, by an earlier mount that crashed
+    /// before taking them off the journal, so are checked first where repeating them would
+    /// do harm. Replayed uploads over files someone else changed meanwhile are dealt with by
+    /// `conflict`.
+    pub fn apply(
+        &self,
+        api: &SeafileAPI,
+        record: &Record,
+        replay: bool,
+        conflict: ConflictPolicy,
+    ) -> seafileapi::Result<Option<String>> {
+        let library_id = &record.library_id;
+        let root = Path::new("/");
+        match &record.operation {
+            Operation::Create { path } => {
+                if !(replay && exists(api, library_id, path)?) {
+                    success(api.create_file(library_id, path)?)?;
+                }
+                Ok(None)
+            }
+            Operation::Mkdir { path } => {
+                if !(replay && exists(api, library_id, path)?) {
+                    success(api.create_new_directory(library_id, path)?)?;
+                }
+                Ok(None)
+            }
+            Operation::Delete { path, is_dir } => {
+                if replay && !exists(api, library_id, path)? {
+                    return Ok(None);
+                }
+                match is_dir {
+                    true => success(api.delete_directory(library_id, path)?)?,
+                    false => success(api.delete_file(library_id, path)?)?,
+                }
+                Ok(None)
+            }
+            Operation::Rename { from, to, is_dir } => {
+                if replay && !exists(api, library_id, from)? && exists(api, library_id, to)? {
+                    return Ok(None);
+                }
+                let from_dir = from.parent().unwrap_or(root);
+                let to_dir = to.parent().unwrap_or(root);
+                let from_name = from.file_name().unwrap_or_default().to_string_lossy();
+                let to_name = to.file_name().unwrap_or_default().to_string_lossy();
+                if from_dir != to_dir {
+                    api.move_entry(library_id, from_dir, &from_name, to_dir)?;
+                }
+                if from_name != to_name {
+                    api.rename(library_id, &to_dir.join(&*from_name), *is_dir, &to_name)?;
+                }
+                Ok(None)
+            }
+            Operation::Upload {
+                path,
+                replace,
+                base_id,
+            } => {
+                let (mut path, mut replace) = (path.clone(), *replace);
+                // Kept until made, if the operation is saved as a conflict copy
+                let mut _claim = None;
+                if let (true, true, Some(base_id)) = (replay, replace, base_id) {
+                    let current = find(api, library_id, &path)?;
+                    if current.is_some_and(|current| current.id != *base_id) {
+                        debug!("journal: {:?} changed on the server", path);
+                        match conflict {
+                            ConflictPolicy::Copy => {
+                                path = conflict_copy(&path, api.username());
+                                replace = false;
+                                // Saved first, so a crash meanwhile does not make a second copy
+                                let operation = Operation::Upload {
+                                    path: path.clone(),
+                                    replace,
+                                    base_id: None,
+                                };
+                                let copy = Record {
+                                    operation,
+                                    ..record.clone()
+                                };
+                                _claim = Some(self.save(&copy)?);
+                            }
+                            ConflictPolicy::Fail => {
+                                return Err(format!("{:?} changed on the server", path).into())
+                            }
+                            ConflictPolicy::Overwrite => {}
+                        }
+                    }
+                }
+                // Uploads beside a file go to a name of their own, so are made already if it is
+                // taken
+                if replay && !replace {
+                    if let Some(uploaded) = find(api, library_id, &path)? {
+                        return Ok(Some(uploaded.id));
+                    }
+                }
+                let mut content = File::open(self.data_path(record.id))?;
+                let size = content.metadata()?.len();
+                let parent = path.parent().unwrap_or(root);
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                let id = if size > UPLOAD_CHUNK_SIZE {
+                    api.upload_file_chunked(library_id, parent, &name, &mut content, size, replace)?
+                } else {
+                    let mut buffer = Vec::with_capacity(size as usize);
+                    content.read_to_end(&mut buffer)?;
+                    let buffer = Bytes::from(buffer);
+                    match replace {
+                        true => {
+                            let link = api.get_update_link(library_id)?;
+                            api.update_file(&link, &path, buffer)?
+                        }
+                        false => {
+                            let link = api.get_upload_link(library_id, parent)?;
+                            api.upload_file(&link, parent, None, &name, buffer, false)?
+                        }
+                    }
+                };
+                Ok(Some(id))
+            }
+        }
+    }
+
+    /// Make the pending operations, failed ones too, in order, calling `done` after each,
+    /// waiting for anyone else making them to finish first. Returns how many were made.
+    pub fn replay(
+        &self,
+        api: &SeafileAPI,
+        conflict: ConflictPolicy,
+        done: impl FnMut(&Record),
+    ) -> seafileapi::Result<usize> {
+        self.replay_records(api, conflict, true, done)
+    }
+
+    /// Make the pending operations in order, calling `done` after each, until the server is
+    /// unreachable. Those that fail, or that someone else is making, hold up the ones to be made
+    /// after them. If `asked`, failed operations are made again, and those someone else is
+    /// making are waited for, else both are left. Returns how many were made, or the first
+    /// error.
+    fn replay_records(
+        &self,
+        api: &SeafileAPI,
+        conflict: ConflictPolicy,
+        asked: bool,
+        mut done: impl FnMut(&Record),
+    ) -> seafileapi::Result<usize> {
+        let mut count = 0;
+        let mut held_up = Vec::<Record>::new();
+        let mut error = None;
+        for mut record in self.records()? {
+            if (record.failed && !asked) || held_up.iter().any(|held| held.holds_up(&record)) {
+                held_up.push(record);
+                continue;
+            }
+            let _claim = match self.claim(record.id, asked)? {
+                Some(claim) => claim,
+                None => {
+                    held_up.push(record);
+                    continue;
+                }
+            };
+            match self.apply(api, &record, true, conflict) {
+                Ok(_) => {
+                    info!("journal: replayed {:?}", record.operation);
+                    self.remove(record.id)?;
+                    done(&record);
+                    count += 1;
+                }
+                Err(e) if seafileapi::is_offline(&e) => return Err(e),
+                Err(e) => {
+                    warn!("journal: replaying {:?} failed: {}", record.operation, e);
+                    record.attempts += 1;
+                    record.error = Some(e.to_string());
+                    record.failed = refused(&e);
+                    self.save(&record)?;
+                    if !record.failed {
+                        held_up.push(record);
+                    }
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(count),
+        }
+    }
+
+    /// Have the replay started by `spawn_replay` look for operations to make now.
+    fn wake(&self) {
+        // Kept for the replay to find if it is busy, rather than waiting, meanwhile
+        self.wakeup.notify_one();
+    }
+
+    /// Replay pending operations, dropping the listings they change from `directories`.
+    fn replay_pending(
+        &self,
+        api: &SeafileAPI,
+        conflict: ConflictPolicy,
+        directories: &DirectoryCache,
+    ) {
+        let result = self.replay_records(api, conflict, false, |record| {
+            for path in record.operation.paths() {
+                if let Some(parent) = path.parent() {
+                    directories.expire(&(record.library_id.clone(), parent.to_path_buf()));
+                }
+            }
+        });
+        if let Err(e) = result {
+            debug!("journal: replay stopped: {}", e);
+        }
+    }
+
+    /// Replay pending operations when woken, or every `REPLAY_INTERVAL`, in a task on the
+    /// runtime. Whoever holds the lock meanwhile is left to it.
+    pub(crate) fn spawn_replay(
+        self: Arc<Self>,
+        api: Arc<SeafileAPI>,
+        conflict: ConflictPolicy,
+        directories: Arc<DirectoryCache>,
+    ) {
+        runtime().spawn(async move {
+            loop {
+                let (journal, api, directories) = (self.clone(), api.clone(), directories.clone());
+                let replay = task::spawn_blocking(move || {
+                    journal.replay_pending(&api, conflict, &directories);
+                });
+                if let Err(e) = replay.await {
+                    warn!("journal: replay failed: {}", e);
+                }
+                let _ = time::timeout(REPLAY_INTERVAL, self.wakeup.notified()).await;
+            }
+        });
+    }
+
+    /// Read the recorded contents behind a listing id given by `overlay`, if it is one.
+    pub(crate) fn read(&self, file_id: &str, offset: u64, size: u64) -> Option<io::Result<Bytes>> {
+        let id = file_id.strip_prefix(PENDING_ID)?.parse::<u64>().ok()?;
+        let content = match File::open(self.data_path(id)) {
+            Ok(content) => content,
+            // Created empty, or made meanwhile, so to be read from the server
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let created = self
+                    .with_records(|records| {
+                        records.get(&id).is_some_and(|record| {
+                            matches!(record.operation, Operation::Create { .. })
+                        })
+                    })
+                    .ok()?;
+                return created.then(|| Ok(Bytes::new()));
+            }
+            Err(e) => return Some(Err(e)),
+        };
+        let mut buffer = vec![0; size as usize];
+        let mut read = 0;
+        while read < buffer.len() {
+            match content.read_at(&mut buffer[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        buffer.truncate(read);
+        Some(Ok(Bytes::from(buffer)))
+    }
+
+    /// Where the directory `dir` in the library `library_id` is on the server until the
+    /// pending operations are made, or `None` if it is not there yet.
+    pub(crate) fn origin(&self, library_id: &str, dir: &Path) -> Option<PathBuf> {
+        self.with_records(|records| {
+            let records = records
+                .values()
+                .filter(|record| record.library_id == library_id && !record.failed);
+            before(dir, records)
+        })
+        .unwrap_or_else(|_| Some(dir.to_path_buf()))
+    }
+
+    /// `entries` of `dir` in the library `library_id` as they will be once the pending
+    /// operations are made, or `None` if none concern it.
+    pub(crate) fn overlay(
+        &self,
+        library_id: &str,
+        dir: &Path,
+        entries: &[LibraryEntry],
+    ) -> Option<Vec<LibraryEntry>> {
+        let records = self
+            .with_records(|records| {
+                let records = records
+                    .values()
+                    .filter(|record| record.library_id == library_id && !record.failed)
+                    .collect::<Vec<_>>();
+                // Each with where `dir` was when it was recorded, as directories renamed since
+                // take their contents along
+                (0..records.len())
+                    .filter_map(|i| {
+                        let dir = before(dir, records[i + 1..].iter().copied())?;
+                        let concerned = records[i]
+                            .operation
+                            .paths()
+                            .iter()
+                            .any(|path| path.parent() == Some(&dir));
+                        concerned.then(|| (records[i].clone(), dir))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if records.is_empty() {
+            return None;
+        }
+        let mut entries = entries.to_vec();
+        let name_of = |path: &Path| {
+            path.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string()
+        };
+        for (record, dir) in &records {
+            let name_in_dir = |path: &Path| (path.parent() == Some(dir)).then(|| name_of(path));
+            let add = |entries: &mut Vec<LibraryEntry>, path: &Path, entry: LibraryEntry| {
+                if let Some(name) = name_in_dir(path) {
+                    entries.retain(|existing| existing.name != name);
+                    entries.push(LibraryEntry { name, ..entry });
+                }
+            };
+            let pending = LibraryEntry {
+                id: format!("{}{}", PENDING_ID, record.id),
+                mtime: record.queued.max(0) as u64,
+                ..LibraryEntry::default()
+            };
+            match &record.operation {
+                Operation::Create { path } => add(
+                    &mut entries,
+                    path,
+                    LibraryEntry {
+                        entry_type: "file".to_string(),
+                        ..pending
+                    },
+                ),
+                Operation::Upload { path, .. } => {
+                    let size = fs::metadata(self.data_path(record.id)).map_or(0, |m| m.len());
+                    let existing = name_in_dir(path)
+                        .and_then(|name| entries.iter().find(|entry| entry.name == name).cloned());
+                    add(
+                        &mut entries,
+                        path,
+                        LibraryEntry {
+                            entry_type: "file".to_string(),
+                            size,
+                            ..existing.map_or(pending.clone(), |existing| LibraryEntry {
+                                id: pending.id.clone(),
+                                mtime: pending.mtime,
+                                ..existing
+                            })
+                        },
+                    )
+                }
+                Operation::Mkdir { path } => add(
+                    &mut entries,
+                    path,
+                    LibraryEntry {
+                        entry_type: "dir".to_string(),
+                        ..pending
+                    },
+                ),
+                Operation::Delete { path, .. } => {
+                    if let Some(name) = name_in_dir(path) {
+                        entries.retain(|entry| entry.name != name);
+                    }
+                }
+                Operation::Rename { from, to, is_dir } => {
+                    let moved = name_in_dir(from).and_then(|name| {
+                        let position = entries.iter().position(|entry| entry.name == name)?;
+                        Some(entries.remove(position))
+                    });
+                    let entry_type = match is_dir {
+                        true => "dir",
+                        false => "file",
+                    };
+                    add(
+                        &mut entries,
+                        to,
+                        moved.unwrap_or(LibraryEntry {
+                            entry_type: entry_type.to_string(),
+                            ..pending
+                        }),
+                    )
+                }
+            }
+        }
+        Some(entries)
+    }
+}
+
+impl SeafileFS {
+    /// Make `operation` through the journal: recorded, then made straight away unless
+    /// operations recorded earlier that it is to be made after are still pending, or the server
+    /// is unreachable, in which case it is left for replay. Returns the new file id of uploads
+    /// made straight away.
+    pub(crate) fn journaled(
+        &self,
+        library_id: &str,
+        operation: Operation,
+        content: Option<&mut File>,
+    ) -> Result<Option<String>, c_int> {
+        let journal = &self.journal;
+        let io_errno = |e: io::Error| {
+            warn!("journal: cannot record in {:?}: {}", journal.dir, e);
+            e.raw_os_error().unwrap_or(EIO)
+        };
+        // Claimed as recorded, so no replay can make the operation meanwhile
+        let (record, claim) = journal
+            .append_claimed(library_id, operation, content)
+            .map_err(io_errno)?;
+        let result = match journal.waits(&record).map_err(io_errno) {
+            Ok(true) => {
+                info!(
+                    "journal: queued {:?} behind pending operations",
+                    record.operation
+                );
+                Ok(None)
+            }
+            Ok(false) => match journal.apply(&self.api, &record, false, self.options.conflict) {
+                Ok(id) => journal.remove(record.id).map(|_| id).map_err(io_errno),
+                Err(e) if seafileapi::is_offline(&e) => {
+                    info!("journal: queued {:?} while offline", record.operation);
+                    Ok(None)
+                }
+                Err(e) => {
+                    debug!("ERROR: {:?} {}", record.operation, e);
+                    let _ = journal.remove(record.id);
+                    Err(errno(&e))
+                }
+            },
+            Err(e) => Err(e),
+        };
+        drop(claim);
+        journal.wake();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, entry_type: &str, size: u64) -> LibraryEntry {
+        LibraryEntry {
+            id: format!("id-{}", name),
+            name: name.to_string(),
+            entry_type: entry_type.to_string(),
+            size,
+            ..LibraryEntry::default()
+        }
+    }
+
+    fn names(entries: &[LibraryEntry]) -> Vec<&str> {
+        let mut names = entries
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn path(path: &str) -> PathBuf {
+        PathBuf::from(path)
+    }
+
+    #[test]
+    fn records_are_kept_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path().join("journal"));
+        assert!(journal.records().unwrap().is_empty());
+        for name in ["/a", "/b", "/c"] {
+            journal
+                .append("lib", Operation::Mkdir { path: path(name) }, None)
+                .unwrap();
+        }
+        let ids = journal
+            .records()
+            .unwrap()
+            .iter()
+            .map(|record| record.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert!(journal.discard(1).unwrap());
+        assert!(!journal.discard(1).unwrap());
+        assert_eq!(journal.records().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn operations_wait_for_earlier_ones_on_the_same_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path().to_path_buf());
+        let operations = [
+            Operation::Mkdir { path: path("/a") },
+            Operation::Create { path: path("/a/x") },
+            Operation::Create { path: path("/b") },
+            Operation::Rename {
+                from: path("/b"),
+                to: path("/a/y"),
+                is_dir: false,
+            },
+        ];
+        for operation in operations {
+            journal.append("lib", operation, None).unwrap();
+        }
+        journal
+            .append("other", Operation::Create { path: path("/a") }, None)
+            .unwrap();
+        let waits = |id: u64| {
+            let records = journal.records().unwrap();
+            let record = records.iter().find(|record| record.id == id).unwrap();
+            journal.waits(record).unwrap()
+        };
+        assert!(!waits(1));
+        assert!(waits(2));
+        assert!(!waits(3));
+        assert!(waits(4));
+        assert!(!waits(5));
+        // Failed operations hold up nothing
+        let mut mkdir = journal.records().unwrap().remove(0);
+        mkdir.failed = true;
+        journal.save(&mkdir).unwrap();
+        assert!(!waits(2));
+        journal.discard(3).unwrap();
+        assert!(!waits(4));
+    }
+
+    #[test]
+    fn records_are_read_back_by_a_new_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path().to_path_buf());
+        journal
+            .append("lib", Operation::Create { path: path("/a") }, None)
+            .unwrap();
+        journal
+            .append("lib", Operation::Create { path: path("/b") }, None)
+            .unwrap();
+        journal.discard(1).unwrap();
+        let reopened = Journal::new(dir.path().to_path_buf());
+        let records = reopened.records().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, 2);
+        let record = reopened
+            .append("lib", Operation::Create { path: path("/c") }, None)
+            .unwrap();
+        assert_eq!(record.id, 3);
+    }
+
+    #[test]
+    fn journals_sharing_a_directory_take_distinct_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let mount = Journal::new(dir.path().to_path_buf());
+        let cli = Journal::new(dir.path().to_path_buf());
+        let create = |name: &str| Operation::Create { path: path(name) };
+        assert_eq!(mount.append("lib", create("/a"), None).unwrap().id, 1);
+        assert_eq!(cli.append("lib", create("/b"), None).unwrap().id, 2);
+        assert_eq!(mount.append("lib", create("/c"), None).unwrap().id, 3);
+        // Nor are the ids of operations made given out again
+        cli.discard(3).unwrap();
+        assert_eq!(mount.append("lib", create("/d"), None).unwrap().id, 4);
+        assert_eq!(cli.records().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn uploads_recorded_without_a_base_id_are_read() {
+        let json = r#"{"id":1,"library_id":"lib","operation":"upload","path":"/a","replace":true,"queued":0}"#;
+        let record = serde_json::from_str::<Record>(json).unwrap();
+        assert!(matches!(
+            record.operation,
+            Operation::Upload { base_id: None, .. }
+        ));
+    }
+
+    #[test]
+    fn uploads_are_read_from_the_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path().to_path_buf());
+        let mut content = tempfile::tempfile().unwrap();
+        content.write_all(b"hello, journal").unwrap();
+        let upload = journal
+            .append(
+                "lib",
+                Operation::Upload {
+                    path: path("/a"),
+                    replace: false,
+                    base_id: None,
+                },
+                Some(&mut content),
+            )
+            .unwrap();
+        let create = journal
+            .append("lib", Operation::Create { path: path("/b") }, None)
+            .unwrap();
+        let id = |record: &Record| format!("{}{}", PENDING_ID, record.id);
+        let read = |file_id: &str, offset, size| journal.read(file_id, offset, size);
+        assert_eq!(read(&id(&upload), 7, 100).unwrap().unwrap(), "journal");
+        assert!(read(&id(&create), 0, 100).unwrap().unwrap().is_empty());
+        assert!(read("id-from-the-server", 0, 100).is_none());
+        assert!(read(&format!("{}99", PENDING_ID), 0, 100).is_none());
+    }
+
+    #[test]
+    fn overlay_shows_pending_operations_as_done() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path().to_path_buf());
+        let listing = [
+            entry("kept", "file", 1),
+            entry("deleted", "file", 1),
+            entry("renamed", "file", 5),
+            entry("moved", "dir", 0),
+        ];
+        let operations = [
+            Operation::Create {
+                path: path("/docs/created"),
+            },
+            Operation::Mkdir {
+                path: path("/docs/made"),
+            },
+            Operation::Delete {
+                path: path("/docs/deleted"),
+                is_dir: false,
+            },
+            Operation::Rename {
+                from: path("/docs/renamed"),
+                to: path("/docs/new name"),
+                is_dir: false,
+            },
+            Operation::Rename {
+                from: path("/docs/moved"),
+                to: path("/elsewhere/moved"),
+                is_dir: true,
+            },
+            Operation::Rename {
+                from: path("/elsewhere/arrived"),
+                to: path("/docs/arrived"),
+                is_dir: true,
+            },
+        ];
+        for operation in operations {
+            journal.append("lib", operation, None).unwrap();
+        }
+        let entries = journal
+            .overlay("lib", Path::new("/docs"), &listing)
+            .unwrap();
+        assert_eq!(
+            names(&entries),
+            vec!["arrived", "created", "kept", "made", "new name"]
+        );
+        let find = |name: &str| entries.iter().find(|entry| entry.name == name).unwrap();
+        assert_eq!(find("created").entry_type, "file");
+        assert!(find("created").id.starts_with(PENDING_ID));
+        assert_eq!(find("made").entry_type, "dir");
+        assert_eq!(find("arrived").entry_type, "dir");
+        // Renamed entries keep what the server said of them
+        assert_eq!(find("new name").id, "id-renamed");
+        assert_eq!(find("new name").size, 5);
+        assert_eq!(find("kept").id, "id-kept");
+    }
+
+    #[test]
+    fn overlay_applies_operations_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path().to_path_buf());
+        let listing = [entry("recreated", "file", 1)];
+        let operations = [
+            Operation::Delete {
+                path: path("/recreated"),
+                is_dir: false,
+            },
+            Operation::Mkdir {
+                path: path("/recreated"),
+            },
+            Operation::Create {
+                path: path("/short-lived"),
+            },
+            Operation::Delete {
+                path: path("/short-lived"),
+                is_dir: false,
+            },
+            Operation::Create { path: path("/a") },
+            Operation::Rename {
+                from: path("/a"),
+                to: path("/b"),
+                is_dir: false,
+            },
+            Operation::Rename {
+                from: path("/b"),
+                to: path("/c"),
+                is_dir: false,
+            },
+        ];
+        for operation in operations {
+            journal.append("lib", operation, None).unwrap();
+        }
+        let entries = journal.overlay("lib", Path::new("/"), &listing).unwrap();
+        assert_eq!(names(&entries), vec!["c", "recreated"]);
+        let recreated = entries.iter().find(|e| e.name == "recreated").unwrap();
+        assert_eq!(recreated.entry_type, "dir");
+        let c = entries.iter().find(|e| e.name == "c").unwrap();
+        assert_eq!(c.id, format!("{}5", PENDING_ID));
+    }
+
+    #[test]
+    fn directories_made_or_renamed_are_listed_before_being_made() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path().to_path_buf());
+        let operations = [
+            Operation::Mkdir {
+                path: path("/made"),
+            },
+            Operation::Create {
+                path: path("/made/a"),
+            },
+            Operation::Create {
+                path: path("/old/created"),
+            },
+            Operation::Rename {
+                from: path("/old"),
+                to: path("/moved"),
+                is_dir: true,
+            },
+            Operation::Create {
+                path: path("/moved/sub/later"),
+            },
+        ];
+        for operation in operations {
+            journal.append("lib", operation, None).unwrap();
+        }
+        assert_eq!(journal.origin("lib", Path::new("/made")), None);
+        assert_eq!(journal.origin("lib", Path::new("/made/sub")), None);
+        assert_eq!(
+            journal.origin("lib", Path::new("/moved/sub")),
+            Some(path("/old/sub"))
+        );
+        assert_eq!(
+            journal.origin("lib", Path::new("/other")),
+            Some(path("/other"))
+        );
+        let entries = journal.overlay("lib", Path::new("/made"), &[]).unwrap();
+        assert_eq!(names(&entries), vec!["a"]);
+        // Listed from where the directory is on the server
+        let listing = [entry("kept", "file", 1)];
+        let entries = journal
+            .overlay("lib", Path::new("/moved"), &listing)
+            .unwrap();
+        assert_eq!(names(&entries), vec!["created", "kept"]);
+        let entries = journal
+            .overlay("lib", Path::new("/moved/sub"), &[])
+            .unwrap();
+        assert_eq!(names(&entries), vec!["later"]);
+        assert!(journal
+            .overlay("lib", Path::new("/old"), &listing)
+            .is_none());
+    }
+
+    #[test]
+    fn overlay_of_uploads_gives_the_recorded_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path().to_path_buf());
+        let listing = [LibraryEntry {
+            permission: "rw".to_string(),
+            ..entry("existing", "file", 1)
+        }];
+        let mut content = tempfile::tempfile().unwrap();
+        content.write_all(&[0; 1000]).unwrap();
+        for name in ["/existing", "/new"] {
+            let operation = Operation::Upload {
+                path: path(name),
+                replace: true,
+                base_id: None,
+            };
+            journal
+                .append("lib", operation, Some(&mut content))
+                .unwrap();
+        }
+        let entries = journal.overlay("lib", Path::new("/"), &listing).unwrap();
+        let find = |name: &str| entries.iter().find(|entry| entry.name == name).unwrap();
+        assert_eq!(find("existing").size, 1000);
+        assert_eq!(find("existing").permission, "rw");
+        assert_eq!(find("existing").id, format!("{}1", PENDING_ID));
+        assert_eq!(find("new").size, 1000);
+        assert_eq!(find("new").entry_type, "file");
+    }
+
+    #[test]
+    fn overlay_ignores_other_directories_and_libraries() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path().to_path_buf());
+        let listing = [entry("a", "file", 1)];
+        assert!(journal.overlay("lib", Path::new("/"), &listing).is_none());
+        journal
+            .append(
+                "lib",
+                Operation::Create {
+                    path: path("/sub/a"),
+                },
+                None,
+            )
+            .unwrap();
+        journal
+            .append("other", Operation::Create { path: path("/b") }, None)
+            .unwrap();
+        assert!(journal.overlay("lib", Path::new("/"), &listing).is_none());
+        let entries = journal.overlay("other", Path::new("/"), &listing).unwrap();
+        assert_eq!(names(&entries), vec!["a", "b"]);
+    }
+}
@@ -1,36 +1,294 @@
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use cache::{BlockCache, TtlCache, BLOCK_SIZE};
 use fuse_mt::{
-    CallbackResult, DirectoryEntry, FileAttr, FileType, FilesystemMT, RequestInfo, ResultCreate,
-    ResultEmpty, ResultEntry, ResultOpen, ResultReaddir, ResultSlice, ResultStatfs, ResultWrite,
-    Statfs,
+    CallbackResult, CreatedEntry, DirectoryEntry, FileAttr, FileType, FilesystemMT, RequestInfo,
+    ResultCreate, ResultEmpty, ResultEntry, ResultOpen, ResultReaddir, ResultSlice, ResultStatfs,
+    ResultWrite, ResultXattr, Statfs, Xattr,
 };
-use libc::{ENOENT, ENOSYS, EPERM};
+use handle::{DirectoryHandles, Handle, Handles};
+use journal::Operation;
+use libc::{EEXIST, EISDIR, ENOENT, ENOSYS, ENOTDIR, EPERM, ERANGE, EROFS, EXDEV};
+use libc::{O_ACCMODE, O_RDONLY, O_TRUNC};
 use libc::{S_IFMT, S_IFREG, S_IRGRP, S_IROTH, S_IRUSR, S_IRWXG, S_IRWXO, S_IRWXU, S_IWUSR};
-use log::{debug, info, trace};
+use log::{debug, info};
+use resolve::{errno, Location};
+use seafileapi::{Commit, CommitEntry, FileRevision, LibraryEntry, ShareLink, TrashItem};
 use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
+pub mod asyncapi;
+mod attributes;
+pub mod cache;
+mod handle;
+mod history;
+pub mod journal;
+mod lock;
+mod mount;
+mod notify;
+pub mod options;
+pub mod pin;
+mod readahead;
+mod resolve;
 pub mod seafileapi;
+mod snapshots;
+mod tls;
+mod trash;
+mod writeback;
+
+pub use mount::Mount;
+pub use notify::Poller;
+pub use options::MountOptions;
 
 static TTL: Duration = Duration::from_secs(1);
 
+/// Directory listings by library id and path
+type DirectoryCache = TtlCache<(String, PathBuf), Arc<Vec<LibraryEntry>>>;
+/// File revisions by library id and path
+type HistoryCache = TtlCache<(String, PathBuf), Arc<Vec<FileRevision>>>;
+/// Named trash items by library id
+type TrashCache = TtlCache<String, Arc<Vec<(String, TrashItem)>>>;
+/// Share links by library id
+type ShareLinkCache = TtlCache<String, Arc<Vec<ShareLink>>>;
+
 pub struct SeafileFS {
-    api: seafileapi::SeafileAPI,
+    api: Arc<seafileapi::SeafileAPI>,
+    options: MountOptions,
+    handles: Arc<Handles>,
+    directory_handles: Arc<DirectoryHandles>,
+    // Shared with the poller, which drops what changed on the server
+    directories: Arc<DirectoryCache>,
+    history: Arc<HistoryCache>,
+    commits: Arc<TtlCache<String, Arc<Vec<Commit>>>>,
+    snapshot_directories: TtlCache<(String, String, PathBuf), Arc<Vec<CommitEntry>>>,
+    trash: Arc<TrashCache>,
+    share_links: ShareLinkCache,
+    blocks: Arc<BlockCache>,
+    journal: Arc<journal::Journal>,
+    pins: Arc<pin::Pins>,
+}
+
+/// The blocks `first..=last` of the contents with id `file_id`, through the block cache.
+///
+/// Only the blocks missing from the cache are downloaded, in a single ranged request through
+/// `download`, given the offset and size. Blocks being downloaded already are waited for
+/// instead.
+fn fetch_blocks(
+    cache: &BlockCache,
+    file_id: &str,
+    first: u64,
+    last: u64,
+    download: impl FnOnce(u64, u64) -> seafileapi::Result<Bytes>,
+) -> seafileapi::Result<Vec<Bytes>> {
+    let (mut blocks, fetching) = cache.claim(file_id, first..=last, true);
+    if let Some(fetching) = fetching {
+        let (from, to) = (*fetching.blocks.start(), *fetching.blocks.end());
+        let mut data = download(from * BLOCK_SIZE, (to - from + 1) * BLOCK_SIZE)?;
+        for block in from..=to {
+            let chunk = data.split_to((BLOCK_SIZE as usize).min(data.len()));
+            cache.insert(file_id, block, chunk.clone());
+            blocks[(block - first) as usize] = Some(chunk);
+        }
+    }
+    Ok(blocks.into_iter().flatten().collect())
+}
+
+/// `size` bytes at `offset` of the contents with id `file_id`, `file_size` bytes long, from the
+/// blocks `fetch_blocks` gives.
+fn read_range(
+    cache: &BlockCache,
+    file_id: &str,
+    file_size: u64,
+    offset: u64,
+    size: u64,
+    download: impl FnOnce(u64, u64) -> seafileapi::Result<Bytes>,
+) -> seafileapi::Result<Bytes> {
+    let end = (offset + size).min(file_size);
+    if offset >= end {
+        return Ok(Bytes::new());
+    }
+    let first = offset / BLOCK_SIZE;
+    let last = (end - 1) / BLOCK_SIZE;
+    let blocks = fetch_blocks(cache, file_id, first, last, download)?;
+    let mut body = BytesMut::new();
+    for block in blocks {
+        body.extend_from_slice(&block);
+    }
+    let body = body.freeze();
+    let skip = ((offset - first * BLOCK_SIZE) as usize).min(body.len());
+    let take = ((end - offset) as usize).min(body.len() - skip);
+    Ok(body.slice(skip..skip + take))
 }
 
 impl SeafileFS {
-    pub fn new(server: &OsString, username: &OsString, password: &OsString) -> SeafileFS {
-        SeafileFS {
-            api: seafileapi::SeafileAPI::new(
-                &server.to_string_lossy(),
-                &username.to_string_lossy(),
-                &password.to_string_lossy(),
-            ),
+    pub fn new(
+        server: &str,
+        username: &str,
+        password: &str,
+        options: MountOptions,
+    ) -> seafileapi::Result<SeafileFS> {
+        let api = seafileapi::SeafileAPI::with_options(
+            server,
+            username,
+            password,
+            options.client.clone(),
+        )?;
+        Ok(SeafileFS {
+            api: Arc::new(api),
+            handles: Arc::new(Handles::new()),
+            directory_handles: Arc::new(DirectoryHandles::new()),
+            directories: Arc::new(TtlCache::new(options.metadata_ttl)),
+            history: Arc::new(TtlCache::new(options.metadata_ttl)),
+            commits: Arc::new(TtlCache::new(options.metadata_ttl)),
+            snapshot_directories: TtlCache::new(options.metadata_ttl),
+            trash: Arc::new(TtlCache::new(options.metadata_ttl)),
+            share_links: TtlCache::new(options.metadata_ttl),
+            blocks: Arc::new(BlockCache::new(options.cache_size)),
+            journal: Arc::new(journal::Journal::new(
+                options
+                    .journal
+                    .clone()
+                    .unwrap_or_else(|| journal::Journal::default_dir(server, username)),
+            )),
+            pins: Arc::new(pin::Pins::new(
+                options
+                    .pins
+                    .clone()
+                    .unwrap_or_else(|| pin::Pins::default_dir(server, username)),
+            )),
+            options,
+        })
+    }
+
+    /// Lock state sharing this filesystem's handles.
+    fn locks(&self) -> lock::Locks {
+        lock::Locks::new(
+            self.api.clone(),
+            self.handles.clone(),
+            self.options.server_locks,
+        )
+    }
+
+    /// Log in, to find out before mounting whether the server refuses the credentials or the
+    /// connection. Being offline is not an error: the mount serves what it can until the
+    /// server is back.
+    pub fn check_login(&self) -> seafileapi::Result<()> {
+        match self.api.get_account_info() {
+            Err(e) if !seafileapi::is_offline(&e) => Err(e),
+            _ => Ok(()),
         }
     }
 
-    fn fileattr(req: RequestInfo, kind: FileType, perm: u16, size: u64, mtime: u64) -> FileAttr {
+    /// A poller for changes to this filesystem's libraries, unless polling is disabled.
+    fn poller(&self, inodes: Arc<notify::Inodes>) -> Option<Poller> {
+        Some(Poller {
+            api: self.api.clone(),
+            directories: self.directories.clone(),
+            history: self.history.clone(),
+            commits: self.commits.clone(),
+            trash: self.trash.clone(),
+            pins: self.pins.clone(),
+            inodes,
+            interval: self.options.poll_interval?,
+        })
+    }
+
+    /// Directory listing, through the metadata cache, with the operations pending in the
+    /// journal applied.
+    fn list(&self, library_id: &str, path: &Path) -> seafileapi::Result<Arc<Vec<LibraryEntry>>> {
+        let entries = match self.journal.origin(library_id, path) {
+            Some(origin) if origin == path => self.list_server(library_id, path)?,
+            // Renamed, and not yet on the server under its new name
+            Some(origin) => match self.list_server(library_id, &origin) {
+                Err(e) if seafileapi::is_offline(&e) => Arc::new(Vec::new()),
+                entries => entries?,
+            },
+            // Not yet made on the server
+            None => Arc::new(Vec::new()),
+        };
+        Ok(match self.journal.overlay(library_id, path, &entries) {
+            Some(entries) => Arc::new(entries),
+            None => entries,
+        })
+    }
+
+    /// Directory listing as on the server, through the metadata cache.
+    fn list_server(
+        &self,
+        library_id: &str,
+        path: &Path,
+    ) -> seafileapi::Result<Arc<Vec<LibraryEntry>>> {
+        let key = (library_id.to_string(), path.to_path_buf());
+        if let Some(entries) = self.directories.get(&key) {
+            return Ok(entries);
+        }
+        let entries = match self.api.get_library_content(library_id, path) {
+            Ok(entries) => Arc::new(entries),
+            // Offline, the last listing seen is better than none
+            Err(e) if seafileapi::is_offline(&e) => {
+                return match self.directories.get_stale(&key) {
+                    Some(entries) => Ok(entries),
+                    None => self.pins.listing(library_id, path).map(Arc::new).ok_or(e),
+                };
+            }
+            Err(e) => return Err(e),
+        };
+        self.directories.insert(key, entries.clone());
+        Ok(entries)
+    }
+
+    /// Forget the cached listing of the directory containing `path`.
+    fn invalidate_parent(&self, library_id: &str, path: &Path) {
+        if let Some(parent) = path.parent() {
+            self.directories
+                .expire(&(library_id.to_string(), parent.to_path_buf()));
+        }
+    }
+
+    /// Read `size` bytes at `offset` from `file`, through the block cache.
+    fn read_blocks(
+        &self,
+        library_id: &str,
+        path: &Path,
+        file: &LibraryEntry,
+        offset: u64,
+        size: u64,
+    ) -> seafileapi::Result<Bytes> {
+        if let Some(content) = self.journal.read(&file.id, offset, size) {
+            return Ok(content?);
+        }
+        if let Some(content) = self.pins.read(&file.id, offset, size) {
+            return Ok(content?);
+        }
+        self.read_cached(&file.id, file.size, offset, size, |offset, size| {
+            self.api
+                .download_file_range(library_id, path, &file.id, offset, size)
+        })
+    }
+
+    /// Read `size` bytes at `offset` from the contents with id `file_id`, through the block
+    /// cache.
+    fn read_cached(
+        &self,
+        file_id: &str,
+        file_size: u64,
+        offset: u64,
+        size: u64,
+        download: impl FnOnce(u64, u64) -> seafileapi::Result<Bytes>,
+    ) -> seafileapi::Result<Bytes> {
+        read_range(&self.blocks, file_id, file_size, offset, size, download)
+    }
+
+    fn fileattr(
+        &self,
+        owner: Option<&str>,
+        kind: FileType,
+        perm: u16,
+        size: u64,
+        mtime: u64,
+    ) -> FileAttr {
+        let (uid, gid) = self.options.owner(owner);
         FileAttr {
             size,
             blocks: 100u64,
@@ -39,19 +297,35 @@ impl SeafileFS {
             ctime: SystemTime::UNIX_EPOCH + Duration::from_secs(mtime),
             crtime: SystemTime::UNIX_EPOCH,
             kind,
-            perm,
+            perm: perm & !self.options.mask(kind == FileType::Directory),
             nlink: 0u32,
-            uid: req.uid,
-            gid: req.gid,
+            uid,
+            gid,
             rdev: 0u32,
             flags: 0,
         }
     }
 }
 
+/// Reply to `getxattr` or `listxattr` with `value`, or its size if `size` is 0.
+fn xattr(value: Vec<u8>, size: u32) -> ResultXattr {
+    match size {
+        0 => Ok(Xattr::Size(value.len() as u32)),
+        size if value.len() > size as usize => Err(ERANGE),
+        _ => Ok(Xattr::Data(value)),
+    }
+}
+
 impl FilesystemMT for SeafileFS {
     fn init(&self, _req: RequestInfo) -> ResultEmpty {
         info!("init");
+        self.journal.clone().spawn_replay(
+            self.api.clone(),
+            self.options.conflict,
+            self.directories.clone(),
+        );
+        self.pins
+            .spawn_sync(self.api.clone(), self.pins.libraries());
         Ok(())
     }
 
@@ -59,93 +333,77 @@ impl FilesystemMT for SeafileFS {
         info!("destroy");
     }
 
-    fn getattr(&self, req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
+    fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
         debug!("getattr: {:?}", path);
-        let mut components = path.components().collect::<Vec<_>>();
-        debug!("getattr: {:?}", components);
+        let staged_size = fh
+            .and_then(|fh| self.handles.location(fh))
+            .and_then(|location| location.staged_size());
 
-        match components.len() {
-            2 => {
-                let mut libraries = match self.api.get_libraries() {
-                    Ok(l) => l,
-                    Err(e) => {
-                        debug!("ERROR: readdir({:?}) {}", path, e);
-                        return Err(ENOENT);
-                    }
-                };
-                trace!("Seafile libraries: {:?}", libraries);
-                libraries.sort_by(|a, b| a.name.cmp(&b.name));
-                libraries.dedup_by(|a, b| a.name.eq(&b.name));
-                let entry = match libraries
-                    .into_iter()
-                    .find(|entry| entry.name.eq(&components[1].as_os_str().to_string_lossy()))
-                {
-                    Some(e) => e,
-                    _ => {
-                        debug!("ERROR: 0: readdir({:?})", path);
-                        return Err(ENOENT);
-                    }
+        match self.resolve(path)? {
+            Location::Root => Ok((TTL, self.fileattr(None, FileType::Directory, 0o755, 0, 0))),
+            Location::Library(library) => Ok((
+                TTL,
+                self.fileattr(
+                    Some(&library.owner),
+                    FileType::Directory,
+                    0o755,
+                    library.size,
+                    library.mtime,
+                ),
+            )),
+            Location::Entry { library, path } => {
+                let e = self.stat(&library, &path)?;
+                debug!("Found {:?} as match of {:?}", e, path);
+                let (kind, perm) = match e.entry_type.as_str() {
+                    "dir" => (FileType::Directory, 0o755_u16),
+                    _ => (FileType::RegularFile, 0o644_u16),
                 };
-
+                let size = staged_size.unwrap_or(e.size);
                 Ok((
                     TTL,
-                    SeafileFS::fileattr(req, FileType::Directory, 0o755, entry.size, entry.mtime),
+                    self.fileattr(Some(&library.owner), kind, perm, size, e.mtime),
                 ))
             }
-            1 => Ok((
+            Location::History { library, .. } | Location::FileHistory { library, .. } => Ok((
                 TTL,
-                SeafileFS::fileattr(req, FileType::Directory, 0o755, 0, 0),
+                self.fileattr(Some(&library.owner), FileType::Directory, 0o555, 0, 0),
             )),
-            _ => {
-                let mut libraries = match self.api.get_libraries() {
-                    Ok(l) => l,
-                    Err(e) => {
-                        debug!("ERROR: A: readdir({:?}) {}", path, e);
-                        return Err(ENOENT);
-                    }
-                };
-                libraries.sort_by(|a, b| a.name.cmp(&b.name));
-                libraries.dedup_by(|a, b| a.name.eq(&b.name));
-                debug!("Seafile libraries: {:?}", libraries);
-                let library_name = components.remove(1);
-                let file_name = components.pop().unwrap();
-                debug!(
-                    "split: ({:?} | {:?} | {:?})",
-                    library_name, components, file_name
-                );
-                let relative_path = components.into_iter().collect::<PathBuf>();
-                debug!("join: {:?}", relative_path);
-                let entry = match libraries
-                    .into_iter()
-                    .find(|entry| entry.name.eq(&library_name.as_os_str().to_string_lossy()))
-                {
-                    Some(e) => e,
-                    _ => seafileapi::Library::default(),
-                };
-                let entries = match self.api.get_library_content(&entry.id, &relative_path) {
-                    Ok(e) => e,
-                    Err(e) => {
-                        debug!("ERROR: B: readdir({:?}) {}", path, e);
-                        return Err(ENOENT);
-                    }
-                };
-                debug!(
-                    "TODO: Find {:?} in entries of {}: {:?}",
-                    path, entry.name, entries
-                );
-                let e = match entries
-                    .into_iter()
-                    .find(|entry| entry.name.eq(&file_name.as_os_str().to_string_lossy()))
-                {
-                    Some(e) => e,
-                    _ => return Err(ENOENT),
-                };
-                debug!("Found {:?} as match of {:?}", e, path);
-                let (kind, perm, size, mtime) = match e.entry_type.as_str() {
-                    "dir" => (FileType::Directory, 0o755_u16, e.size, e.mtime),
-                    _ => (FileType::RegularFile, 0o644_u16, e.size, e.mtime),
+            Location::Revision {
+                library, revision, ..
+            } => Ok((
+                TTL,
+                self.fileattr(
+                    Some(&library.owner),
+                    FileType::RegularFile,
+                    0o444,
+                    revision.rev_file_size,
+                    revision.ctime,
+                ),
+            )),
+            Location::Snapshots(library) | Location::Trash(library) => Ok((
+                TTL,
+                self.fileattr(
+                    Some(&library.owner),
+                    FileType::Directory,
+                    0o555,
+                    0,
+                    library.mtime,
+                ),
+            )),
+            Location::Snapshot {
+                library,
+                commit,
+                entry,
+                ..
+            } => {
+                let (kind, perm, size) = match entry {
+                    Some(entry) if !entry.is_dir => (FileType::RegularFile, 0o444, entry.size),
+                    _ => (FileType::Directory, 0o555, 0),
                 };
-                Ok((TTL, SeafileFS::fileattr(req, kind, perm, size, mtime)))
+                Ok((
+                    TTL,
+                    self.fileattr(Some(&library.owner), kind, perm, size, commit.ctime),
+                ))
             }
         }
     }
@@ -165,97 +423,25 @@ impl FilesystemMT for SeafileFS {
         })
     }
 
-    fn mkdir(&self, req: RequestInfo, parent: &Path, name: &OsStr, _mode: u32) -> ResultEntry {
-        debug!("mkdir: {:?} in {:?} {:?}", name, parent, parent.parent());
-        if parent.parent().is_none() {
-            return Err(EPERM);
-        }
-        let mut libraries = match self.api.get_libraries() {
-            Ok(l) => l,
-            Err(e) => {
-                debug!("ERROR: mkdir({:?} {:?}) {}", parent, name, e);
-                return Err(ENOENT);
-            }
+    fn mkdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr, _mode: u32) -> ResultEntry {
+        debug!("mkdir: {:?} in {:?}", name, parent);
+        let (library, relative_path) = self.resolve_child(parent, name)?;
+        let operation = Operation::Mkdir {
+            path: relative_path.clone(),
         };
-        debug!("Seafile libraries: {:?}", libraries);
-        libraries.sort_by(|a, b| a.name.cmp(&b.name));
-        libraries.dedup_by(|a, b| a.name.eq(&b.name));
-
-        let mut components = parent.components().collect::<Vec<_>>();
-        let library_name = components.remove(1);
-        debug!("split: ({:?}, {:?})", library_name, components);
-        let mut relative_path = components.into_iter().collect::<PathBuf>();
-        relative_path.push(name);
-        debug!("join: {:?}", relative_path);
-        let library = match libraries
-            .into_iter()
-            .find(|entry| entry.name.eq(&library_name.as_os_str().to_string_lossy()))
-        {
-            Some(e) => e,
-            _ => {
-                debug!("ERROR: no library {:?}", library_name);
-                return Err(ENOENT);
-            }
-        };
-        let result = match self
-            .api
-            .create_new_directory(&library.id, relative_path.as_path())
-        {
-            Ok(e) => e,
-            Err(e) => {
-                debug!("ERROR: mkdir({:?} {:?}) {}", parent, name, e);
-                return Err(ENOENT);
-            }
-        };
-
-        debug!(
-            "TODO create {:?} in {:?}: {:?}",
-            relative_path, library_name, result
-        );
-
-        if result == "\"success\"" {
-            return Ok((
-                TTL,
-                SeafileFS::fileattr(req, FileType::Directory, 0o755, 0, 0),
-            ));
-        }
-
-        Err(ENOSYS)
+        self.journaled(&library.id, operation, None)?;
+        debug!("create {:?} in {:?}", relative_path, library.name);
+        self.invalidate_parent(&library.id, &relative_path);
+        Ok((
+            TTL,
+            self.fileattr(Some(&library.owner), FileType::Directory, 0o755, 0, 0),
+        ))
     }
     fn rmdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
-        debug!("rmdir: {:?} in {:?} {:?}", name, parent, parent.parent());
+        debug!("rmdir: {:?} in {:?}", name, parent);
 
-        if parent.parent().is_none() {
-            return Err(EPERM);
-        }
-        let mut libraries = match self.api.get_libraries() {
-            Ok(l) => l,
-            Err(e) => {
-                debug!("ERROR: mkdir({:?} {:?}) {}", parent, name, e);
-                return Err(ENOENT);
-            }
-        };
-        debug!("Seafile libraries: {:?}", libraries);
-        libraries.sort_by(|a, b| a.name.cmp(&b.name));
-        libraries.dedup_by(|a, b| a.name.eq(&b.name));
-
-        let mut components = parent.components().collect::<Vec<_>>();
-        let library_name = components.remove(1);
-        debug!("split: ({:?}, {:?})", library_name, components);
-        let mut relative_path = components.into_iter().collect::<PathBuf>();
-        relative_path.push(name);
-        debug!("join: {:?}", relative_path);
-
-        let _library = match libraries
-            .into_iter()
-            .find(|entry| entry.name.eq(&library_name.as_os_str().to_string_lossy()))
-        {
-            Some(e) => e,
-            _ => {
-                debug!("ERROR: no library {:?}", library_name);
-                return Err(ENOENT);
-            }
-        };
+        let (library, relative_path) = self.resolve_child(parent, name)?;
+        self.stat_dir(&library, &relative_path)?;
         Err(ENOSYS)
         // DO NOT PROCEED WITH THIS -- NEED TO TEST WHETHER EMPTY FIRST - SEAFILE WILL *WIPE* CONTENTS
         /*
@@ -263,11 +449,11 @@ impl FilesystemMT for SeafileFS {
                     Ok(e) => e,
                     Err(e) => {
                         debug!("ERROR: rmdir({:?} {:?}) {}", parent, name, e);
-                        return Err(ENOENT);
+                        return Err(errno(&e));
                     }
                 };
 
-                debug!("TODO remove {:?} from {:?}: {:?}", relative_path, library_name, result);
+                debug!("TODO remove {:?} from {:?}: {:?}", relative_path, library.name, result);
 
                 if result == "\"success\"" {
                     return Ok(());
@@ -279,90 +465,205 @@ impl FilesystemMT for SeafileFS {
 
     fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
         debug!("opendir: {:?} (flags = {:#o})", path, _flags);
-        match path.parent() {
-            None => Ok((0, 0)),
-            Some(_) => Ok((0, 0)), //Err(EPERM)
+        // Handles are only needed to tell the directories of libraries apart
+        match self.resolve(path)? {
+            Location::Library(library) => Ok((
+                self.directory_handles
+                    .insert(&library.id, PathBuf::from("/")),
+                0,
+            )),
+            Location::Entry { library, path } => {
+                Ok((self.directory_handles.insert(&library.id, path), 0))
+            }
+            _ => Ok((0, 0)),
         }
     }
 
+    fn releasedir(&self, _req: RequestInfo, path: &Path, fh: u64, _flags: u32) -> ResultEmpty {
+        debug!("releasedir: {:?}", path);
+        self.directory_handles.remove(fh);
+        Ok(())
+    }
+
     fn readdir(&self, _req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
         debug!("readdir: {:?}", path);
 
-        let mut libraries = match self.api.get_libraries() {
-            Ok(l) => l,
-            Err(e) => {
-                debug!("ERROR: readdir({:?}) {}", path, e);
-                return Err(ENOENT);
-            }
-        };
-        debug!("Seafile libraries: {:?}", libraries);
-        libraries.sort_by(|a, b| a.name.cmp(&b.name));
-        libraries.dedup_by(|a, b| a.name.eq(&b.name));
-
-        let entries = match path.parent() {
-            None => libraries
-                .into_iter()
-                .map(|entry| DirectoryEntry {
-                    name: OsString::from(entry.name),
-                    kind: FileType::Directory,
-                })
-                .collect(),
-            Some(_) => {
-                let mut components = path.components().collect::<Vec<_>>();
-                let library_name = components.remove(1);
-                debug!("split: ({:?}, {:?})", library_name, components);
-                let relative_path = components.into_iter().collect::<PathBuf>();
-                debug!("join: {:?}", relative_path);
-                let library = match libraries
-                    .into_iter()
-                    .find(|entry| entry.name.eq(&library_name.as_os_str().to_string_lossy()))
-                {
-                    Some(e) => e,
-                    _ => {
-                        debug!("ERROR: no library {:?}", library_name);
-                        return Err(ENOENT);
-                    }
-                };
-                let entries = match self
-                    .api
-                    .get_library_content(&library.id, relative_path.as_path())
-                {
-                    Ok(e) => e,
-                    Err(e) => {
-                        debug!("ERROR: readdir({:?}) {}", path, e);
-                        return Err(ENOENT);
-                    }
-                };
-                entries
+        let (library, relative_path) = match self.resolve(path)? {
+            Location::Root => {
+                let libraries = self.libraries()?;
+                debug!("Seafile libraries: {:?}", libraries);
+                return Ok(libraries
                     .into_iter()
                     .map(|entry| DirectoryEntry {
                         name: OsString::from(entry.name),
-                        kind: match entry.entry_type.as_str() {
-                            "dir" => FileType::Directory,
-                            _ => FileType::RegularFile,
+                        kind: FileType::Directory,
+                    })
+                    .collect());
+            }
+            Location::Library(library) => (library, PathBuf::from("/")),
+            Location::Entry { library, path } => {
+                self.stat_dir(&library, &path)?;
+                (library, path)
+            }
+            Location::History { library, dir } => {
+                let entries = self.list(&library.id, &dir).map_err(|e| {
+                    debug!("ERROR: readdir({:?}) {}", path, e);
+                    errno(&e)
+                })?;
+                return Ok(entries
+                    .iter()
+                    .filter(|entry| entry.entry_type != "dir")
+                    .map(|entry| DirectoryEntry {
+                        name: OsString::from(&entry.name),
+                        kind: FileType::Directory,
+                    })
+                    .collect());
+            }
+            Location::FileHistory { library, file } => {
+                let revisions = self.file_history(&library.id, &file)?;
+                return Ok(revisions
+                    .iter()
+                    .map(|revision| DirectoryEntry {
+                        name: OsString::from(history::revision_name(revision)),
+                        kind: FileType::RegularFile,
+                    })
+                    .collect());
+            }
+            Location::Revision { .. } => return Err(libc::ENOTDIR),
+            Location::Snapshots(library) => {
+                let commits = self.snapshots(&library.id)?;
+                return Ok(commits
+                    .iter()
+                    .map(|commit| DirectoryEntry {
+                        name: OsString::from(history::timestamp(commit.ctime)),
+                        kind: FileType::Directory,
+                    })
+                    .collect());
+            }
+            Location::Trash(library) => {
+                let items = self.trash(&library.id)?;
+                return Ok(items
+                    .iter()
+                    .map(|(name, item)| DirectoryEntry {
+                        name: OsString::from(name),
+                        kind: match item.is_dir {
+                            true => FileType::Directory,
+                            false => FileType::RegularFile,
                         },
                     })
-                    .collect()
+                    .collect());
+            }
+            Location::Snapshot {
+                library,
+                commit,
+                path,
+                entry,
+            } => {
+                if entry.is_some_and(|entry| !entry.is_dir) {
+                    return Err(libc::ENOTDIR);
+                }
+                let entries = self.list_snapshot(&library.id, &commit.id, &path)?;
+                return Ok(entries
+                    .iter()
+                    .map(|entry| DirectoryEntry {
+                        name: OsString::from(&entry.name),
+                        kind: match entry.is_dir {
+                            true => FileType::Directory,
+                            false => FileType::RegularFile,
+                        },
+                    })
+                    .collect());
             }
         };
+        let entries = self.list(&library.id, &relative_path).map_err(|e| {
+            debug!("ERROR: readdir({:?}) {}", path, e);
+            errno(&e)
+        })?;
+        let entries = entries
+            .iter()
+            .map(|entry| DirectoryEntry {
+                name: OsString::from(&entry.name),
+                kind: match entry.entry_type.as_str() {
+                    "dir" => FileType::Directory,
+                    _ => FileType::RegularFile,
+                },
+            })
+            .collect::<Vec<_>>();
         debug!("readdir {:?}: {:?}", path, entries);
 
         Ok(entries)
     }
 
-    fn truncate(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>, size: u64) -> ResultEmpty {
+    fn truncate(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, size: u64) -> ResultEmpty {
         debug!("truncate: {:?} to {:#x}", path, size);
-        Ok(())
+        if let Some(handle) = fh.and_then(|fh| self.handles.get(fh)) {
+            let mut handle = handle.lock().unwrap();
+            if handle.writable {
+                self.stage(&mut handle, size == 0)?;
+                return SeafileFS::truncate_staged(&mut handle, size);
+            }
+        }
+        let (library, relative_path) = match self.resolve(path)? {
+            Location::Entry { library, path } => (library, path),
+            Location::Revision { .. } | Location::Snapshot { .. } => return Err(EROFS),
+            _ => return Err(EISDIR),
+        };
+        // The kernel does not always say which handle; truncate any local copies instead
+        let mut staged = false;
+        for handle in self
+            .handles
+            .find(&library.id, &relative_path)
+            .into_iter()
+            .filter_map(|fh| self.handles.get(fh))
+        {
+            let mut handle = handle.lock().unwrap();
+            if handle.writable {
+                self.stage(&mut handle, size == 0)?;
+                SeafileFS::truncate_staged(&mut handle, size)?;
+                staged = true;
+            }
+        }
+        if staged {
+            return Ok(());
+        }
+        let mut handle = Handle::new(library, relative_path);
+        self.stage(&mut handle, size == 0)?;
+        SeafileFS::truncate_staged(&mut handle, size)?;
+        self.write_back(&mut handle)
     }
 
     fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
         debug!("open {:?} {:#o}", path, flags);
-        Err(libc::ENOSYS)
+        let flags = flags as i32;
+        let (library, relative_path) = match self.resolve(path)? {
+            Location::Entry { library, path } => (library, path),
+            Location::Revision { .. } | Location::Snapshot { .. }
+                if flags & O_ACCMODE == O_RDONLY =>
+            {
+                return Ok((0, 0))
+            }
+            Location::Revision { .. } | Location::Snapshot { .. } => return Err(EROFS),
+            _ => return Err(EISDIR),
+        };
+        let entry = self.stat(&library, &relative_path)?;
+        let mut handle = Handle::new(library, relative_path);
+        handle.base_id = Some(entry.id);
+        handle.writable = flags & O_ACCMODE != O_RDONLY;
+        // Truncating needs no contents; otherwise they are fetched on the first write, which
+        // unlike open does not hold up the session
+        if handle.writable && flags & O_TRUNC != 0 {
+            self.stage(&mut handle, true)?;
+            handle.dirty = true;
+        }
+        Ok((self.handles.insert(handle), 0))
     }
 
     fn flush(&self, _req: RequestInfo, path: &Path, fh: u64, _lock_owner: u64) -> ResultEmpty {
         debug!("flush: {:?} {}", path, fh);
-        Ok(())
+        match self.handles.get(fh) {
+            Some(handle) => self.write_back(&mut handle.lock().unwrap()),
+            None => Ok(()),
+        }
     }
 
     fn release(
@@ -375,76 +676,95 @@ impl FilesystemMT for SeafileFS {
         _flush: bool,
     ) -> ResultEmpty {
         debug!("release: {:?} {}", path, fh);
-        Ok(())
+        match self.handles.remove(fh) {
+            Some(handle) => self.write_back(&mut handle.lock().unwrap()),
+            None => Ok(()),
+        }
     }
 
     fn fsync(&self, _req: RequestInfo, path: &Path, fh: u64, _datasync: bool) -> ResultEmpty {
         debug!("fsync: {:?} {}", path, fh);
-        Ok(())
+        match self.handles.get(fh) {
+            Some(handle) => self.write_back(&mut handle.lock().unwrap()),
+            None => Ok(()),
+        }
     }
 
     fn read(
         &self,
         _req: RequestInfo,
         path: &Path,
-        _fh: u64,
+        fh: u64,
         offset: u64,
         size: u32,
         callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult,
     ) -> CallbackResult {
         debug!("read {:?} {} {}", path, offset, size);
-        let mut components = path.components().collect::<Vec<_>>();
-        debug!("read: {:?}", components);
 
-        let mut libraries = match self.api.get_libraries() {
-            Ok(l) => l,
-            Err(e) => {
-                debug!("ERROR: A: read({:?}) {}", path, e);
-                return callback(Err(ENOENT));
-            }
-        };
-        libraries.sort_by(|a, b| a.name.cmp(&b.name));
-        libraries.dedup_by(|a, b| a.name.eq(&b.name));
-        debug!("Seafile libraries: {:?}", libraries);
-        let library_name = components.remove(1);
-        debug!("split: ({:?} | {:?})", library_name, components);
-        let relative_path = components.into_iter().collect::<PathBuf>();
-        debug!("join: {:?}", relative_path);
-        let entry = match libraries
-            .into_iter()
-            .find(|entry| entry.name.eq(&library_name.as_os_str().to_string_lossy()))
-        {
-            Some(e) => e,
-            _ => seafileapi::Library::default(),
-        };
-        let download_uri = match self.api.get_download_link(&entry.id, &relative_path) {
-            Ok(e) => e,
-            Err(e) => {
-                debug!("ERROR: B: read({:?}) {}", path, e);
-                return callback(Err(ENOENT));
+        if let Some(handle) = self.handles.get(fh) {
+            if let Some(result) = SeafileFS::read_staged(&handle.lock().unwrap(), offset, size) {
+                return match result {
+                    Ok(body) => callback(Ok(&body)),
+                    Err(e) => callback(Err(e)),
+                };
             }
+        }
+
+        let result = match self.resolve(path) {
+            Ok(Location::Entry { library, path }) => match self.stat(&library, &path) {
+                Ok(file) => {
+                    if let Some(handle) = self.handles.get(fh) {
+                        let mut handle = handle.lock().unwrap();
+                        self.read_ahead(&mut handle, &file, offset, size as u64);
+                    }
+                    self.read_blocks(&library.id, &path, &file, offset, size as u64)
+                }
+                Err(e) => return callback(Err(e)),
+            },
+            Ok(Location::Revision {
+                library,
+                file,
+                revision,
+            }) => self.read_cached(
+                &revision.rev_file_id,
+                revision.rev_file_size,
+                offset,
+                size as u64,
+                |offset, size| {
+                    let link = self
+                        .api
+                        .get_file_revision_link(&library.id, &file, &revision.id)?;
+                    self.api.download_range(&link, offset, size)
+                },
+            ),
+            Ok(Location::Snapshot {
+                library,
+                commit,
+                path,
+                entry: Some(entry),
+            }) if !entry.is_dir => self.read_cached(
+                &entry.obj_id,
+                entry.size,
+                offset,
+                size as u64,
+                |offset, size| {
+                    let link = self
+                        .api
+                        .get_file_revision_link(&library.id, &path, &commit.id)?;
+                    self.api.download_range(&link, offset, size)
+                },
+            ),
+            Ok(_) => return callback(Err(EISDIR)),
+            Err(e) => return callback(Err(e)),
         };
-        debug!(
-            "TODO: Find {:?} in entries of {}: {:?}",
-            path, entry.name, download_uri
-        );
-        let mut body = match self.api.download(&download_uri) {
-            Ok(e) => e,
+        let body = match result {
+            Ok(body) => body,
             Err(e) => {
-                debug!("ERROR: C: read({:?}) {}", path, e);
-                return callback(Err(ENOENT));
+                debug!("ERROR: read({:?}) {}", path, e);
+                return callback(Err(errno(&e)));
             }
         };
-        if body.len() > offset as usize {
-            body = body.split_off(offset as usize);
-        } else {
-            debug!("body len: {} emptying", body.len());
-            body = Bytes::new();
-        }
-        if body.len() > size as usize {
-            body = body.split_to(size as usize);
-        }
-        debug!("body: {:?}", body);
+        debug!("body: {} bytes", body.len());
 
         callback(Ok(&body))
     }
@@ -453,18 +773,24 @@ impl FilesystemMT for SeafileFS {
         &self,
         _req: RequestInfo,
         path: &Path,
-        _fh: u64,
+        fh: u64,
         offset: u64,
         data: Vec<u8>,
         flags: u32,
     ) -> ResultWrite {
         debug!("write {:?} {} {} {:#o}", path, offset, data.len(), flags);
-        Ok(data.len() as u32)
+        let handle = self.handles.get(fh).ok_or(libc::EBADF)?;
+        let mut handle = handle.lock().unwrap();
+        if !handle.writable {
+            return Err(libc::EBADF);
+        }
+        self.stage(&mut handle, false)?;
+        SeafileFS::write_staged(&mut handle, offset, &data)
     }
 
     fn mknod(
         &self,
-        req: RequestInfo,
+        _req: RequestInfo,
         parent: &Path,
         name: &OsStr,
         mode: u32,
@@ -489,58 +815,17 @@ impl FilesystemMT for SeafileFS {
             return Err(EPERM);
         }
         // Can only create within a library
-        if parent.parent().is_none() {
-            return Err(EPERM);
-        }
-
-        let mut libraries = match self.api.get_libraries() {
-            Ok(l) => l,
-            Err(e) => {
-                debug!("ERROR: mkdir({:?} {:?}) {}", parent, name, e);
-                return Err(ENOENT);
-            }
-        };
-        debug!("Seafile libraries: {:?}", libraries);
-        libraries.sort_by(|a, b| a.name.cmp(&b.name));
-        libraries.dedup_by(|a, b| a.name.eq(&b.name));
-
-        let mut components = parent.components().collect::<Vec<_>>();
-        let library_name = components.remove(1);
-        debug!("split: ({:?}, {:?})", library_name, components);
-        let mut relative_path = components.into_iter().collect::<PathBuf>();
-        relative_path.push(name);
-        debug!("join: {:?}", relative_path);
-
-        let library = match libraries
-            .into_iter()
-            .find(|entry| entry.name.eq(&library_name.as_os_str().to_string_lossy()))
-        {
-            Some(e) => e,
-            _ => {
-                debug!("ERROR: no library {:?}", library_name);
-                return Err(ENOENT);
-            }
-        };
-        let result = match self.api.create_file(&library.id, relative_path.as_path()) {
-            Ok(e) => e,
-            Err(e) => {
-                debug!("ERROR: mknod({:?} {:?}) {}", parent, name, e);
-                return Err(ENOENT);
-            }
+        let (library, relative_path) = self.resolve_child(parent, name)?;
+        let operation = Operation::Create {
+            path: relative_path.clone(),
         };
-
-        debug!(
-            "TODO create {:?} in {:?}: {:?}",
-            relative_path, library_name, result
-        );
-
-        if result == "\"success\"" {
-            return Ok((
-                TTL,
-                SeafileFS::fileattr(req, FileType::RegularFile, 0o644, 0, 0),
-            ));
-        }
-        Err(EPERM)
+        self.journaled(&library.id, operation, None)?;
+        debug!("create {:?} in {:?}", relative_path, library.name);
+        self.invalidate_parent(&library.id, &relative_path);
+        Ok((
+            TTL,
+            self.fileattr(Some(&library.owner), FileType::RegularFile, 0o644, 0, 0),
+        ))
     }
 
     fn create(
@@ -552,11 +837,259 @@ impl FilesystemMT for SeafileFS {
         flags: u32,
     ) -> ResultCreate {
         debug!("create {:?} {:?} {:?} {:?}", parent, name, mode, flags);
-        Err(ENOSYS)
+        if mode & S_IFMT != S_IFREG {
+            return Err(EPERM);
+        }
+        let (library, relative_path) = self.resolve_child(parent, name)?;
+        let operation = Operation::Create {
+            path: relative_path.clone(),
+        };
+        self.journaled(&library.id, operation, None)?;
+        debug!("create {:?} in {:?}", relative_path, library.name);
+        self.invalidate_parent(&library.id, &relative_path);
+
+        let attr = self.fileattr(Some(&library.owner), FileType::RegularFile, 0o644, 0, 0);
+        let mut handle = Handle::new(library, relative_path);
+        handle.writable = true;
+        self.stage(&mut handle, true)?;
+        Ok(CreatedEntry {
+            ttl: TTL,
+            attr,
+            fh: self.handles.insert(handle),
+            flags: 0,
+        })
+    }
+
+    fn rename(
+        &self,
+        _req: RequestInfo,
+        parent: &Path,
+        name: &OsStr,
+        newparent: &Path,
+        newname: &OsStr,
+    ) -> ResultEmpty {
+        debug!(
+            "rename {:?} {:?} to {:?} {:?}",
+            parent, name, newparent, newname
+        );
+        if let Location::Trash(library) = self.resolve(parent)? {
+            return self.restore(&library, name, self.resolve_child(newparent, newname)?);
+        }
+        let (library, from) = self.resolve_child(parent, name)?;
+        let (target_library, to) = self.resolve_child(newparent, newname)?;
+        if target_library.id != library.id {
+            return Err(EXDEV);
+        }
+        let is_dir = self.stat(&library, &from)?.entry_type == "dir";
+        match self.stat(&library, &to) {
+            // Replacing a file, as editors saving through a temporary file do
+            Ok(target) if target.entry_type != "dir" && !is_dir => {
+                let operation = Operation::Delete {
+                    path: to.clone(),
+                    is_dir: false,
+                };
+                self.journaled(&library.id, operation, None)?;
+            }
+            Ok(target) if target.entry_type != "dir" => return Err(ENOTDIR),
+            Ok(_) if is_dir => return Err(EEXIST),
+            Ok(_) => return Err(EISDIR),
+            Err(ENOENT) => {}
+            Err(e) => return Err(e),
+        }
+        let operation = Operation::Rename {
+            from: from.clone(),
+            to: to.clone(),
+            is_dir,
+        };
+        self.journaled(&library.id, operation, None)?;
+        self.invalidate_parent(&library.id, &from);
+        self.invalidate_parent(&library.id, &to);
+        if is_dir {
+            for key in self.directories.keys() {
+                if key.0 == library.id && key.1.starts_with(&from) {
+                    self.directories.expire(&key);
+                }
+            }
+        }
+        self.handles.rename(&library.id, &from, &to);
+        Ok(())
+    }
+
+    fn getxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
+        debug!("getxattr {:?} {:?} {}", path, name, size);
+        let value = self.attribute(self.resolve(path)?, &name.to_string_lossy())?;
+        xattr(value.into_bytes(), size)
+    }
+
+    fn setxattr(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        name: &OsStr,
+        value: &[u8],
+        _flags: u32,
+        _position: u32,
+    ) -> ResultEmpty {
+        debug!("setxattr {:?} {:?}", path, name);
+        self.set_attribute(self.resolve(path)?, &name.to_string_lossy(), value)
+    }
+
+    fn listxattr(&self, _req: RequestInfo, path: &Path, size: u32) -> ResultXattr {
+        debug!("listxattr {:?} {}", path, size);
+        let mut names = Vec::new();
+        for (name, _) in self.attributes(self.resolve(path)?, true)? {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        xattr(names, size)
     }
 
     fn unlink(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
         debug!("unlink {:?} {:?}", parent, name);
-        Err(ENOSYS)
+        let (library, path) = self.resolve_child(parent, name)?;
+        if self.stat(&library, &path)?.entry_type == "dir" {
+            return Err(EISDIR);
+        }
+        let operation = Operation::Delete {
+            path: path.clone(),
+            is_dir: false,
+        };
+        self.journaled(&library.id, operation, None)?;
+        self.invalidate_parent(&library.id, &path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// `size` bytes of contents which differ from block to block
+    fn contents(size: u64) -> Bytes {
+        (0..size)
+            .map(|i| (i / BLOCK_SIZE + i % 251) as u8)
+            .collect()
+    }
+
+    /// Read through `cache`, downloading from `content` and noting each ranged request in
+    /// `requests`.
+    fn read(
+        cache: &BlockCache,
+        content: &Bytes,
+        requests: &RefCell<Vec<(u64, u64)>>,
+        offset: u64,
+        size: u64,
+    ) -> Bytes {
+        read_range(
+            cache,
+            "f",
+            content.len() as u64,
+            offset,
+            size,
+            |offset, size| {
+                requests.borrow_mut().push((offset, size));
+                let end = (offset + size).min(content.len() as u64);
+                Ok(content.slice(offset as usize..end as usize))
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn read_range_within_and_across_blocks() {
+        let content = contents(3 * BLOCK_SIZE + 100);
+        let cache = BlockCache::new(16 * BLOCK_SIZE);
+        let requests = RefCell::new(Vec::new());
+        let (offset, size) = (10, 20);
+        assert_eq!(
+            read(&cache, &content, &requests, offset, size),
+            content.slice(10..30)
+        );
+        let (offset, size) = (BLOCK_SIZE - 5, BLOCK_SIZE + 10);
+        assert_eq!(
+            read(&cache, &content, &requests, offset, size),
+            content.slice((offset as usize)..((offset + size) as usize))
+        );
+        assert_eq!(
+            *requests.borrow(),
+            vec![(0, BLOCK_SIZE), (BLOCK_SIZE, 2 * BLOCK_SIZE)]
+        );
+    }
+
+    #[test]
+    fn read_range_stops_at_the_end_of_the_file() {
+        let content = contents(BLOCK_SIZE + 100);
+        let cache = BlockCache::new(16 * BLOCK_SIZE);
+        let requests = RefCell::new(Vec::new());
+        let offset = BLOCK_SIZE + 50;
+        assert_eq!(
+            read(&cache, &content, &requests, offset, 4096),
+            content.slice(offset as usize..)
+        );
+        assert!(read(&cache, &content, &requests, BLOCK_SIZE + 100, 10).is_empty());
+        assert!(read(&cache, &content, &requests, 2 * BLOCK_SIZE, 10).is_empty());
+        assert_eq!(*requests.borrow(), vec![(BLOCK_SIZE, BLOCK_SIZE)]);
+    }
+
+    #[test]
+    fn read_range_of_an_empty_file_downloads_nothing() {
+        let cache = BlockCache::new(BLOCK_SIZE);
+        let requests = RefCell::new(Vec::new());
+        assert!(read(&cache, &Bytes::new(), &requests, 0, 4096).is_empty());
+        assert!(requests.borrow().is_empty());
+    }
+
+    #[test]
+    fn read_range_downloads_only_missing_blocks() {
+        let content = contents(4 * BLOCK_SIZE);
+        let cache = BlockCache::new(16 * BLOCK_SIZE);
+        let requests = RefCell::new(Vec::new());
+        read(&cache, &content, &requests, BLOCK_SIZE, 1);
+        assert_eq!(
+            read(&cache, &content, &requests, 0, 2 * BLOCK_SIZE),
+            content.slice(..2 * BLOCK_SIZE as usize)
+        );
+        assert_eq!(
+            read(&cache, &content, &requests, 0, 4 * BLOCK_SIZE),
+            content
+        );
+        assert_eq!(
+            *requests.borrow(),
+            vec![
+                (BLOCK_SIZE, BLOCK_SIZE),
+                (0, BLOCK_SIZE),
+                (2 * BLOCK_SIZE, 2 * BLOCK_SIZE)
+            ]
+        );
+    }
+
+    #[test]
+    fn fetch_blocks_spans_cached_blocks_between_missing_ones() {
+        let content = contents(3 * BLOCK_SIZE);
+        let cache = BlockCache::new(16 * BLOCK_SIZE);
+        cache.insert(
+            "f",
+            1,
+            content.slice(BLOCK_SIZE as usize..2 * BLOCK_SIZE as usize),
+        );
+        let mut requests = Vec::new();
+        let blocks = fetch_blocks(&cache, "f", 0, 2, |offset, size| {
+            requests.push((offset, size));
+            Ok(content.slice(offset as usize..(offset + size) as usize))
+        })
+        .unwrap();
+        assert_eq!(requests, vec![(0, 3 * BLOCK_SIZE)]);
+        assert_eq!(blocks.concat(), content);
+    }
+
+    #[test]
+    fn fetch_blocks_caches_nothing_on_failure() {
+        let cache = BlockCache::new(16 * BLOCK_SIZE);
+        let result = fetch_blocks(&cache, "f", 0, 1, |_, _| Err("offline".into()));
+        assert!(result.is_err());
+        assert_eq!(cache.get("f", 0), None);
+        let (_, fetching) = cache.claim("f", 0..=1, false);
+        assert_eq!(fetching.unwrap().blocks, 0..=1);
     }
 }
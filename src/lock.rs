@@ -0,0 +1,244 @@
+//! Exclusive file locks, held as Seafile file locks so other clients see them too.
+//!
+//! Locks cover whole files, whatever range was asked for. Write locks are taken on the server;
+//! read locks only check nobody else holds a write lock. Everything here may wait on the
+//! server, so is to be called off the FUSE session thread.
+//!
+//! With `server_locks=off`, or once the server turns out not to support locks (only Seafile
+//! Professional does), locks are kept to the mount, and only processes using it see them.
+
+use crate::asyncapi::runtime;
+use crate::handle::Handles;
+use crate::resolve::errno;
+use crate::seafileapi::SeafileAPI;
+use libc::{c_int, EACCES, EAGAIN, ENETDOWN, ENOENT, ENOLCK};
+use log::debug;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, PartialEq)]
+enum State {
+    /// Being taken on the server
+    Locking,
+    Held,
+    /// Being dropped on the server
+    Unlocking,
+}
+
+/// A lock taken through the mount.
+#[derive(Debug)]
+struct Held {
+    /// The lock owner, as given by the kernel
+    owner: u64,
+    /// The handle it was taken through
+    fh: u64,
+    state: State,
+}
+
+#[derive(Debug)]
+pub struct Locks {
+    api: Arc<SeafileAPI>,
+    handles: Arc<Handles>,
+    held: Mutex<HashMap<(String, PathBuf), Held>>,
+    /// Whether locks are taken on the server too
+    server: AtomicBool,
+}
+
+impl Locks {
+    pub fn new(api: Arc<SeafileAPI>, handles: Arc<Handles>, server: bool) -> Self {
+        Self {
+            api,
+            handles,
+            held: Mutex::new(HashMap::new()),
+            server: AtomicBool::new(server),
+        }
+    }
+
+    /// The library id and path of the file open as `fh`.
+    fn file(&self, fh: u64) -> Result<(String, PathBuf), c_int> {
+        let location = self.handles.location(fh).ok_or(ENOLCK)?;
+        Ok((location.library_id.clone(), location.path()))
+    }
+
+    /// Whether another Seafile client or user has locked the file.
+    fn locked_elsewhere(&self, library_id: &str, path: &Path) -> Result<bool, c_int> {
+        if !self.server.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let entries = self
+            .api
+            .get_library_content(library_id, parent)
+            .map_err(|e| {
+                debug!("ERROR: lock status of {:?} {}", path, e);
+                errno(&e)
+            })?;
+        Ok(entries
+            .iter()
+            .any(|entry| entry.name == name && entry.is_locked && !entry.locked_by_me))
+    }
+
+    /// Whether a lock by `owner` on the file open as `fh` would conflict with another.
+    pub fn conflicts(&self, fh: u64, owner: u64) -> Result<bool, c_int> {
+        let key = self.file(fh)?;
+        let held = self.held.lock().unwrap();
+        if let Some(held) = held.get(&key) {
+            return Ok(held.owner != owner || held.state != State::Held);
+        }
+        drop(held);
+        self.locked_elsewhere(&key.0, &key.1)
+    }
+
+    /// Take a write lock for `owner` on the file open as `fh`, failing with EAGAIN if anyone
+    /// else holds one.
+    ///
+    /// The file is marked as being locked while the server is asked, so nobody else gets in
+    /// meanwhile without holding up other locks.
+    pub fn lock(&self, fh: u64, owner: u64) -> Result<(), c_int> {
+        let key = self.file(fh)?;
+        let mut held = self.held.lock().unwrap();
+        match held.get(&key) {
+            Some(lock) if lock.owner == owner && lock.state == State::Held => return Ok(()),
+            Some(_) => return Err(EAGAIN),
+            None => {}
+        }
+        let locking = Held {
+            owner,
+            fh,
+            state: State::Locking,
+        };
+        held.insert(key.clone(), locking);
+        drop(held);
+
+        let result = self.lock_file(&key);
+        let mut held = self.held.lock().unwrap();
+        match held.get_mut(&key) {
+            Some(lock) if lock.owner == owner && lock.state == State::Locking => match result {
+                Ok(()) => {
+                    debug!("locked {:?} for {:#x}", key.1, owner);
+                    lock.state = State::Held;
+                    Ok(())
+                }
+                Err(e) => {
+                    held.remove(&key);
+                    Err(e)
+                }
+            },
+            // Unlocked, or closed, while being locked
+            _ => {
+                drop(held);
+                if result.is_ok() {
+                    let _ = self.unlock_file(&key);
+                }
+                result
+            }
+        }
+    }
+
+    /// Lock the file on the server, unless someone else has.
+    fn lock_file(&self, (library_id, path): &(String, PathBuf)) -> Result<(), c_int> {
+        if self.locked_elsewhere(library_id, path)? {
+            return Err(EAGAIN);
+        }
+        if !self.server.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let e = match self.api.lock_file(library_id, path) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        debug!("ERROR: lock_file({:?}) {}", path, e);
+        match errno(&e) {
+            // Locked by someone else in the meantime
+            EACCES => Err(EAGAIN),
+            ENETDOWN | ENOENT => Err(ENOLCK),
+            // Anything else suggests the server does not support locks
+            _ => {
+                debug!("server does not lock files, keeping locks to the mount");
+                self.server.store(false, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    /// Drop the lock `owner` holds on the file open as `fh`, if any.
+    pub fn unlock(&self, fh: u64, owner: u64) -> Result<(), c_int> {
+        let key = self.file(fh)?;
+        let mut held = self.held.lock().unwrap();
+        match held.get_mut(&key) {
+            Some(lock) if lock.owner == owner && lock.state == State::Held => {
+                lock.state = State::Unlocking;
+            }
+            // Left to `lock` to undo once the server answers
+            Some(lock) if lock.owner == owner && lock.state == State::Locking => {
+                held.remove(&key);
+                return Ok(());
+            }
+            _ => return Ok(()),
+        }
+        drop(held);
+        self.drop_lock(&key)
+    }
+
+    /// Unlock the file on the server, and forget the lock once done.
+    fn drop_lock(&self, key: &(String, PathBuf)) -> Result<(), c_int> {
+        let result = self.unlock_file(key);
+        let mut held = self.held.lock().unwrap();
+        if held
+            .get(key)
+            .is_some_and(|lock| lock.state == State::Unlocking)
+        {
+            held.remove(key);
+        }
+        result
+    }
+
+    fn unlock_file(&self, (library_id, path): &(String, PathBuf)) -> Result<(), c_int> {
+        if !self.server.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        debug!("unlocking {:?}", path);
+        self.api.unlock_file(library_id, path).map_err(|e| {
+            debug!("ERROR: unlock_file({:?}) {}", path, e);
+            errno(&e)
+        })
+    }
+
+    /// Drop the locks taken through `fh`, as it is closed. The server is told in the
+    /// background.
+    pub fn release(self: &Arc<Self>, fh: u64) {
+        let mut held = self.held.lock().unwrap();
+        held.retain(|_, lock| !(lock.fh == fh && lock.state == State::Locking));
+        let keys = held
+            .iter_mut()
+            .filter(|(_, lock)| lock.fh == fh && lock.state == State::Held)
+            .map(|(key, lock)| {
+                lock.state = State::Unlocking;
+                key.clone()
+            })
+            .collect::<Vec<_>>();
+        drop(held);
+        if keys.is_empty() {
+            return;
+        }
+        let locks = self.clone();
+        runtime().spawn_blocking(move || {
+            for key in keys {
+                let _ = locks.drop_lock(&key);
+            }
+        });
+    }
+
+    /// Drop every lock, as the filesystem is unmounted.
+    pub fn release_all(&self) {
+        let held = std::mem::take(&mut *self.held.lock().unwrap());
+        for (key, lock) in held {
+            if lock.state != State::Locking {
+                let _ = self.unlock_file(&key);
+            }
+        }
+    }
+}
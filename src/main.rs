@@ -1,24 +1,809 @@
-use std::ffi::{OsStr, OsString};
-use std::{env, io};
+use bytes::Bytes;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use log::debug;
+use std::ffi::{CString, OsStr};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::{env, fs};
+use upgraded_giggle::journal::{Journal, Operation};
+use upgraded_giggle::pin::Pins;
+use upgraded_giggle::seafileapi::{success, Library, Result, SeafileAPI, UPLOAD_CHUNK_SIZE};
+use upgraded_giggle::MountOptions;
 
 extern crate log;
 
-fn main() -> io::Result<()> {
-    log4rs::init_file("log4rs.yml", Default::default()).unwrap();
-    let args: Vec<OsString> = env::args_os().collect();
+/// Fuse filesystem and command line client for Seafile libraries
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    /// Seafile server, e.g. https://seafile.example.com
+    #[arg(long, env = "SEAFILE_SERVER")]
+    server: Option<String>,
+    /// Seafile username/email
+    #[arg(long, env = "SEAFILE_USERNAME")]
+    username: Option<String>,
+    /// Seafile password, needed by every command but `journal`, `journal-discard` and `pins`
+    #[arg(long, env = "SEAFILE_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+    /// Options, comma separated: mount options as for mount(8) and fstab, and options for the
+    /// connection to the server, which apply to every command
+    #[arg(short = 'o', value_name = "OPTIONS", global = true)]
+    options: Vec<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+impl Cli {
+    /// Server, username and password, for commands that talk to the server.
+    fn account(&self) -> (&str, &str, &str) {
+        let (server, username) = self.user();
+        (server, username, required(&self.password, "password"))
+    }
+
+    /// Server and username, which the local state of the account is kept under.
+    fn user(&self) -> (&str, &str) {
+        (
+            required(&self.server, "server"),
+            required(&self.username, "username"),
+        )
+    }
+
+    fn api(&self, options: &MountOptions) -> Result<SeafileAPI> {
+        let (server, username, password) = self.account();
+        SeafileAPI::with_options(server, username, password, options.client.clone())
+    }
+}
+
+/// The value of the argument `--<name>`, exiting as for any missing argument if not given.
+fn required<'a>(value: &'a Option<String>, name: &str) -> &'a str {
+    value.as_deref().unwrap_or_else(|| {
+        let env = format!("SEAFILE_{}", name.to_uppercase());
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                format!("--{} (or {}) is needed by this command", name, env),
+            )
+            .exit()
+    })
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Mount all libraries as a fuse filesystem
+    Mount {
+        mountpoint: PathBuf,
+        /// Detach from the terminal once mounted
+        #[arg(long)]
+        daemon: bool,
+    },
+    /// List libraries
+    Libraries,
+    /// List a directory, as <library>:<path>
+    Ls { location: Location },
+    /// Download a file, as <library>:<path>, to a local path (or stdout)
+    Get {
+        location: Location,
+        destination: Option<PathBuf>,
+    },
+    /// Upload a local file into a directory, as <library>:<path>
+    Put {
+        source: PathBuf,
+        location: Location,
+        /// Replace an existing file of the same name, rather than uploading alongside it
+        #[arg(long)]
+        replace: bool,
+    },
+    /// Create a directory, as <library>:<path>
+    Mkdir { location: Location },
+    /// Remove a file or directory, as <library>:<path>
+    Rm {
+        location: Location,
+        /// Treat the location as a directory (Seafile removes its contents too)
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Show account and quota information
+    Info,
+    /// Create a share link for a file or directory, as <library>:<path> or a mounted path, and
+    /// print it
+    Share {
+        location: Location,
+        /// Password protecting the link
+        #[arg(long)]
+        password: Option<String>,
+        /// Days until the link expires
+        #[arg(long)]
+        expire_days: Option<u32>,
+    },
+    /// List share links, of a whole library or of one file or directory
+    ShareLinks { location: Location },
+    /// Delete a share link, by its token
+    Unshare { token: String },
+    /// Create a link for others to upload into a directory, as <library>:<path> or a mounted
+    /// path, and print it
+    UploadLink {
+        location: Location,
+        /// Password protecting the link
+        #[arg(long)]
+        password: Option<String>,
+        /// Days until the link expires
+        #[arg(long)]
+        expire_days: Option<u32>,
+    },
+    /// List upload links, of a whole library or of one directory
+    UploadLinks { location: Location },
+    /// Delete an upload link, by its token
+    DeleteUploadLink { token: String },
+    /// List the operations waiting in the write-back journal
+    Journal {
+        /// Journal directory, as given to the mount with journal=
+        #[arg(long)]
+        journal: Option<PathBuf>,
+    },
+    /// Make the operations waiting in the write-back journal now, in order
+    JournalReplay {
+        /// Journal directory, as given to the mount with journal=
+        #[arg(long)]
+        journal: Option<PathBuf>,
+    },
+    /// Drop an operation from the write-back journal, by its id, without making it
+    JournalDiscard {
+        id: u64,
+        /// Journal directory, as given to the mount with journal=
+        #[arg(long)]
+        journal: Option<PathBuf>,
+    },
+    /// Keep a file or directory, as <library>:<path> or a mounted path, available offline, and
+    /// fetch it now
+    Pin {
+        location: Location,
+        /// Pin directory, as given to the mount with pins=
+        #[arg(long)]
+        pins: Option<PathBuf>,
+    },
+    /// Stop keeping a file or directory available offline
+    Unpin {
+        location: Location,
+        /// Pin directory, as given to the mount with pins=
+        #[arg(long)]
+        pins: Option<PathBuf>,
+    },
+    /// List pinned files and directories
+    Pins {
+        /// Pin directory, as given to the mount with pins=
+        #[arg(long)]
+        pins: Option<PathBuf>,
+    },
+}
+
+/// A location within a library, written as `<library>:<path>`, or a path within a mount.
+#[derive(Clone, Debug)]
+struct Location {
+    /// Library name, or id for mounted paths
+    library: String,
+    path: PathBuf,
+}
+
+/// Extended attribute `name` of the local file `path`, if it has one.
+fn getxattr(path: &Path, name: &str) -> Option<String> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let name = CString::new(name).ok()?;
+    let mut buffer = vec![0u8; 4096];
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            buffer.len(),
+        )
+    };
+    if len < 0 {
+        return None;
+    }
+    buffer.truncate(len as usize);
+    String::from_utf8(buffer).ok()
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let library = getxattr(Path::new(s), "user.seafile.library_id");
+        let path = getxattr(Path::new(s), "user.seafile.path");
+        if let (Some(library), Some(path)) = (library, path) {
+            return Ok(Location {
+                library,
+                path: PathBuf::from(path),
+            });
+        }
+        let (library, path) = s.split_once(':').unwrap_or((s, "/"));
+        if library.is_empty() {
+            return Err(format!("no library in {:?}", s));
+        }
+        Ok(Location {
+            library: library.to_string(),
+            path: Path::new("/").join(path),
+        })
+    }
+}
+
+impl Location {
+    fn library(&self, api: &SeafileAPI) -> Result<Library> {
+        api.get_libraries()?
+            .into_iter()
+            .find(|library| library.name == self.library || library.id == self.library)
+            .ok_or_else(|| format!("no library named {:?}", self.library).into())
+    }
+
+    fn parent(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new("/"))
+    }
+
+    fn file_name(&self) -> Option<&OsStr> {
+        self.path.file_name()
+    }
+}
 
-    if args.len() != 5 {
+fn libraries(api: &SeafileAPI) -> Result<()> {
+    let mut libraries = api.get_libraries()?;
+    libraries.sort_by(|a, b| a.name.cmp(&b.name));
+    for library in libraries {
         println!(
-            "usage: {} <server> <username/email> <password> <mountpoint>",
-            &env::args().next().unwrap()
+            "{}\t{}\t{}\t{}\t{}",
+            library.id, library.permission, library.size, library.owner, library.name
         );
-        ::std::process::exit(-1);
     }
+    Ok(())
+}
+
+fn ls(api: &SeafileAPI, location: &Location) -> Result<()> {
+    let library = location.library(api)?;
+    for entry in api.get_library_content(&library.id, &location.path)? {
+        let kind = match entry.entry_type.as_str() {
+            "dir" => 'd',
+            _ => '-',
+        };
+        println!("{}\t{}\t{}\t{}", kind, entry.size, entry.mtime, entry.name);
+    }
+    Ok(())
+}
+
+fn get(api: &SeafileAPI, location: &Location, destination: Option<&Path>) -> Result<()> {
+    let library = location.library(api)?;
+    let link = api.get_download_link(&library.id, &location.path)?;
+    let mut res = api.download_stream(&link)?;
+    match destination {
+        Some(d) if d.is_dir() => {
+            let name = location
+                .file_name()
+                .ok_or("cannot download a library root")?;
+            io::copy(&mut res, &mut fs::File::create(d.join(name))?)?
+        }
+        Some(d) => io::copy(&mut res, &mut fs::File::create(d)?)?,
+        None => io::copy(&mut res, &mut io::stdout().lock())?,
+    };
+    Ok(())
+}
+
+fn put(api: &SeafileAPI, source: &Path, location: &Location, replace: bool) -> Result<()> {
+    let library = location.library(api)?;
+    let name = source
+        .file_name()
+        .ok_or_else(|| format!("{:?} is not a file", source))?
+        .to_string_lossy();
+    let mut file = fs::File::open(source)?;
+    let size = file.metadata()?.len();
+    if size > UPLOAD_CHUNK_SIZE {
+        api.upload_file_chunked(&library.id, &location.path, &name, &mut file, size, replace)?;
+        return Ok(());
+    }
+    let content = Bytes::from(fs::read(source)?);
+    let link = api.get_upload_link(&library.id, &location.path)?;
+    api.upload_file(&link, &location.path, None, &name, content, replace)?;
+    Ok(())
+}
 
-    let (server, username, password, mountpoint) = (&args[1], &args[2], &args[3], &args[4]);
+fn mkdir(api: &SeafileAPI, location: &Location) -> Result<()> {
+    let library = location.library(api)?;
+    success(api.create_new_directory(&library.id, &location.path)?)
+}
 
-    let filesystem = upgraded_giggle::SeafileFS::new(server, username, password);
-    let options = ["-o", "rw", "-o", "fsname=seafile", "-a", "auto_mount"];
-    let options = options.iter().map(|o| o.as_ref()).collect::<Vec<&OsStr>>();
-    fuse_mt::mount(fuse_mt::FuseMT::new(filesystem, 1), mountpoint, &options)
+fn rm(api: &SeafileAPI, location: &Location, recursive: bool) -> Result<()> {
+    let library = location.library(api)?;
+    if location.file_name().is_none() {
+        return Err("refusing to remove a library root".into());
+    }
+    if recursive {
+        success(api.delete_directory(&library.id, &location.path)?)?;
+    } else {
+        let is_dir = api
+            .get_library_content(&library.id, location.parent())?
+            .into_iter()
+            .any(|e| e.entry_type == "dir" && location.file_name() == Some(OsStr::new(&e.name)));
+        if is_dir {
+            return Err(format!("{:?} is a directory, use --recursive", location.path).into());
+        }
+        api.delete_file(&library.id, &location.path)?;
+    }
+    Ok(())
+}
+
+fn info(api: &SeafileAPI) -> Result<()> {
+    let info = api.get_account_info()?;
+    println!("email: {}", info.email);
+    println!("name: {}", info.name);
+    println!("usage: {}", info.usage);
+    // Seafile reports negative totals for unlimited quotas
+    match info.total {
+        total if total < 0 => println!("total: unlimited"),
+        total => println!("total: {}", total),
+    }
+    Ok(())
+}
+
+fn share(
+    api: &SeafileAPI,
+    location: &Location,
+    password: Option<&str>,
+    expire_days: Option<u32>,
+) -> Result<()> {
+    let library = location.library(api)?;
+    let link = api.create_share_link(&library.id, &location.path, password, expire_days)?;
+    println!("{}", link.link);
+    Ok(())
+}
+
+fn share_links(api: &SeafileAPI, location: &Location) -> Result<()> {
+    let library = location.library(api)?;
+    for link in api.get_share_links(&library.id, None)? {
+        // Directories are shared with a trailing slash
+        let path = Path::new(&link.path);
+        if location.file_name().is_some() && path != location.path {
+            continue;
+        }
+        let expires = match (&link.expire_date, link.is_expired) {
+            (_, true) => "expired",
+            (Some(date), false) => date.as_str(),
+            (None, false) => "never",
+        };
+        println!("{}\t{}\t{}\t{}", link.token, expires, link.path, link.link);
+    }
+    Ok(())
+}
+
+fn upload_link(
+    api: &SeafileAPI,
+    location: &Location,
+    password: Option<&str>,
+    expire_days: Option<u32>,
+) -> Result<()> {
+    let library = location.library(api)?;
+    let link = api.create_upload_link(&library.id, &location.path, password, expire_days)?;
+    println!("{}", link.link);
+    Ok(())
+}
+
+fn upload_links(api: &SeafileAPI, location: &Location) -> Result<()> {
+    let library = location.library(api)?;
+    for link in api.get_upload_links(&library.id, None)? {
+        if location.file_name().is_some() && Path::new(&link.path) != location.path {
+            continue;
+        }
+        let expires = match (&link.expire_date, link.is_expired) {
+            (_, true) => "expired",
+            (Some(date), false) => date.as_str(),
+            (None, false) => "never",
+        };
+        println!("{}\t{}\t{}\t{}", link.token, expires, link.path, link.link);
+    }
+    Ok(())
+}
+
+fn open_journal(cli: &Cli, dir: &Option<PathBuf>) -> Journal {
+    Journal::new(dir.clone().unwrap_or_else(|| {
+        let (server, username) = cli.user();
+        Journal::default_dir(server, username)
+    }))
+}
+
+fn list_journal(journal: &Journal) -> Result<()> {
+    for record in journal.records()? {
+        let queued = chrono::DateTime::from_timestamp(record.queued, 0).unwrap_or_default();
+        let (operation, paths) = match &record.operation {
+            Operation::Create { path } => ("create", path.display().to_string()),
+            Operation::Upload { path, .. } => ("upload", path.display().to_string()),
+            Operation::Mkdir { path } => ("mkdir", path.display().to_string()),
+            Operation::Rename { from, to, .. } => {
+                ("rename", format!("{} -> {}", from.display(), to.display()))
+            }
+            Operation::Delete { path, .. } => ("delete", path.display().to_string()),
+        };
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            record.id,
+            queued.to_rfc3339(),
+            record.library_id,
+            operation,
+            paths
+        );
+        if let Some(error) = &record.error {
+            let retried = match record.failed {
+                true => ", refused, left for journal-replay",
+                false => "",
+            };
+            println!("\tfailed {} times{}: {}", record.attempts, retried, error);
+        }
+    }
+    Ok(())
+}
+
+fn open_pins(cli: &Cli, dir: &Option<PathBuf>) -> Pins {
+    Pins::new(dir.clone().unwrap_or_else(|| {
+        let (server, username) = cli.user();
+        Pins::default_dir(server, username)
+    }))
+}
+
+/// List pins, by library name if `api` is given to look the names up, else by library id.
+fn list_pins(api: Option<&SeafileAPI>, pins: &Pins) -> Result<()> {
+    let libraries = match api {
+        Some(api) => api.get_libraries()?,
+        None => Vec::new(),
+    };
+    for pin in pins.pins()? {
+        let library = libraries
+            .iter()
+            .find(|library| library.id == pin.library_id)
+            .map_or(pin.library_id.as_str(), |library| library.name.as_str());
+        println!("{}:{}", library, pin.path.display());
+    }
+    Ok(())
+}
+
+/// Mount, and tell `daemon`, if detached, once mounted.
+fn mount(
+    cli: &Cli,
+    mountpoint: &Path,
+    options: MountOptions,
+    daemon: Option<Daemon>,
+) -> Result<()> {
+    debug!("mount options: {:?}", options);
+    let fuse_options = options.fuse_options();
+    let threads = options.threads;
+    let (server, username, password) = cli.account();
+    let filesystem = upgraded_giggle::SeafileFS::new(server, username, password, options)?;
+    filesystem.check_login()?;
+    let mut mount = upgraded_giggle::Mount::new(filesystem, threads);
+    let poller = mount.poller();
+    let mut session = fuser::Session::new(mount, mountpoint, &fuse_options)?;
+    if let Some(poller) = poller {
+        poller.spawn(session.notifier());
+    }
+    if let Some(daemon) = daemon {
+        daemon.ready()?;
+    }
+    session.run()?;
+    Ok(())
+}
+
+/// Rewrite a `mount.fuse` helper invocation, `<server> <mountpoint> [-f] -o <options>`, into
+/// the equivalent `mount` subcommand, so that fstab entries such as
+///
+/// `https://seafile.example.com /mnt/seafile fuse.upgraded_giggle username=me,password_file=/etc/seafile.pass,_netdev 0 0`
+///
+/// work. Credentials may be given as `username=`, `password=` or `password_file=` options,
+/// or through the environment as usual.
+fn helper_args(args: Vec<String>) -> Result<Vec<String>> {
+    let first = match args.get(1) {
+        Some(first) => first,
+        None => return Ok(args),
+    };
+    let command = Cli::command();
+    if first.starts_with('-')
+        || first == "help"
+        || command.get_subcommands().any(|c| c.get_name() == first)
+    {
+        return Ok(args);
+    }
+
+    let mut positional = Vec::new();
+    let mut options = Vec::new();
+    let mut foreground = false;
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-o" => options.push(rest.next().ok_or("-o needs a value")?.to_string()),
+            "-f" => foreground = true,
+            // mount(8) flags with no meaning here: sloppy, verbose, no mtab
+            "-s" | "-v" | "-n" => {}
+            o if o.starts_with("-o") => options.push(o[2..].to_string()),
+            _ => positional.push(arg.to_string()),
+        }
+    }
+    let (server, mountpoint) = match positional.as_slice() {
+        [server, mountpoint] => (server, mountpoint),
+        _ => return Err("usage: <server> <mountpoint> [-f] [-o options]".into()),
+    };
+
+    let mut rewritten = vec![
+        args[0].to_string(),
+        "--server".to_string(),
+        server.to_string(),
+    ];
+    let mut mount_options = Vec::new();
+    for option in options.iter().flat_map(|o| o.split(',')) {
+        match option.split_once('=') {
+            Some(("username", username)) => {
+                rewritten.extend(["--username".to_string(), username.to_string()])
+            }
+            Some(("password", password)) => {
+                rewritten.extend(["--password".to_string(), password.to_string()])
+            }
+            Some(("password_file", file)) => {
+                let password = fs::read_to_string(file)?;
+                rewritten.extend(["--password".to_string(), password.trim_end().to_string()])
+            }
+            _ => mount_options.push(option),
+        }
+    }
+    rewritten.extend(["mount".to_string(), mountpoint.to_string()]);
+    if !mount_options.is_empty() {
+        rewritten.extend(["-o".to_string(), mount_options.join(",")]);
+    }
+    if !foreground {
+        rewritten.push("--daemon".to_string());
+    }
+    Ok(rewritten)
+}
+
+/// A mount detached from the terminal, whose parent is waiting to hear that it is mounted.
+struct Daemon {
+    ready: File,
+}
+
+impl Daemon {
+    /// Let the parent exit, successfully, and stop writing to the terminal.
+    fn ready(mut self) -> Result<()> {
+        let null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")?;
+        for fd in 0..=2 {
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        self.ready.write_all(b"1")?;
+        Ok(())
+    }
+}
+
+/// Carry on in a child process of a new session. The parent waits until the child is `ready`,
+/// and exits successfully, or until it fails before then, having printed its error to the same
+/// terminal, and exits with its status; so that mount(8) reports whether the mount worked.
+///
+/// The working directory is kept: relative paths (log configuration, mountpoint) still apply.
+fn daemonize() -> Result<Daemon> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let (mut read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error().into()),
+        0 => {
+            drop(read);
+            if unsafe { libc::setsid() } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            Ok(Daemon { ready: write })
+        }
+        child => {
+            drop(write);
+            let mut ready = [0; 1];
+            if read.read(&mut ready).unwrap_or(0) == 1 {
+                process::exit(0);
+            }
+            let mut status = 0;
+            if unsafe { libc::waitpid(child, &mut status, 0) } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            match libc::WIFEXITED(status) {
+                true => process::exit(libc::WEXITSTATUS(status)),
+                false => process::exit(1),
+            }
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse_from(helper_args(env::args().collect())?);
+
+    let daemon = match &cli.command {
+        Command::Mount {
+            mountpoint,
+            daemon: true,
+        } => {
+            if !mountpoint.is_dir() {
+                return Err(format!("mountpoint {:?} is not a directory", mountpoint).into());
+            }
+            Some(daemonize()?)
+        }
+        _ => None,
+    };
+    if let Err(e) = log4rs::init_file("log4rs.yml", Default::default()) {
+        eprintln!("logging not configured: {}", e);
+    }
+
+    let options = MountOptions::parse(&cli.options)?;
+    let api = || cli.api(&options);
+    match &cli.command {
+        Command::Mount { mountpoint, .. } => mount(&cli, mountpoint, options.clone(), daemon),
+        Command::Libraries => libraries(&api()?),
+        Command::Ls { location } => ls(&api()?, location),
+        Command::Get {
+            location,
+            destination,
+        } => get(&api()?, location, destination.as_deref()),
+        Command::Put {
+            source,
+            location,
+            replace,
+        } => put(&api()?, source, location, *replace),
+        Command::Mkdir { location } => mkdir(&api()?, location),
+        Command::Rm {
+            location,
+            recursive,
+        } => rm(&api()?, location, *recursive),
+        Command::Info => info(&api()?),
+        Command::Share {
+            location,
+            password,
+            expire_days,
+        } => share(&api()?, location, password.as_deref(), *expire_days),
+        Command::ShareLinks { location } => share_links(&api()?, location),
+        Command::Unshare { token } => api()?.delete_share_link(token),
+        Command::UploadLink {
+            location,
+            password,
+            expire_days,
+        } => upload_link(&api()?, location, password.as_deref(), *expire_days),
+        Command::UploadLinks { location } => upload_links(&api()?, location),
+        Command::DeleteUploadLink { token } => api()?.delete_upload_link(token),
+        Command::Journal { journal } => list_journal(&open_journal(&cli, journal)),
+        Command::JournalReplay { journal } => {
+            let count = open_journal(&cli, journal).replay(&api()?, options.conflict, |_| {})?;
+            println!("{} operations made", count);
+            Ok(())
+        }
+        Command::JournalDiscard { id, journal } => {
+            match open_journal(&cli, journal).discard(*id)? {
+                true => Ok(()),
+                false => Err(format!("no operation {} in the journal", id).into()),
+            }
+        }
+        Command::Pin { location, pins } => {
+            let api = api()?;
+            let library = location.library(&api)?;
+            let pins = open_pins(&cli, pins);
+            pins.pin(&library.id, &location.path)?;
+            pins.sync(&api, &library.id)
+        }
+        Command::Unpin { location, pins } => {
+            let api = api()?;
+            let library = location.library(&api)?;
+            let pins = open_pins(&cli, pins);
+            if !pins.unpin(&library.id, &location.path)? {
+                return Err(format!("{} is not pinned", location.path.display()).into());
+            }
+            pins.sync(&api, &library.id)
+        }
+        Command::Pins { pins } => {
+            let api = cli.password.is_some().then(api).transpose()?;
+            list_pins(api.as_ref(), &open_pins(&cli, pins))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn helper_args_leaves_commands_alone() {
+        for given in [
+            args(&["upgraded_giggle"]),
+            args(&["upgraded_giggle", "--server", "https://s", "libraries"]),
+            args(&["upgraded_giggle", "mount", "/mnt"]),
+            args(&["upgraded_giggle", "help"]),
+        ] {
+            assert_eq!(helper_args(given.clone()).unwrap(), given);
+        }
+    }
+
+    #[test]
+    fn helper_args_rewrites_mount_helper_invocations() {
+        let rewritten = helper_args(args(&[
+            "mount.fuse.upgraded_giggle",
+            "https://s",
+            "/mnt/seafile",
+            "-o",
+            "rw,username=me,password=secret,_netdev",
+            "-oallow_other",
+        ]))
+        .unwrap();
+        assert_eq!(
+            rewritten,
+            args(&[
+                "mount.fuse.upgraded_giggle",
+                "--server",
+                "https://s",
+                "--username",
+                "me",
+                "--password",
+                "secret",
+                "mount",
+                "/mnt/seafile",
+                "-o",
+                "rw,_netdev,allow_other",
+                "--daemon",
+            ])
+        );
+        Cli::try_parse_from(rewritten).unwrap();
+    }
+
+    #[test]
+    fn helper_args_in_the_foreground_reads_password_files() {
+        let password = env::temp_dir().join(format!("upgraded_giggle-{}.pass", std::process::id()));
+        fs::write(&password, "secret\n").unwrap();
+        let option = format!("password_file={}", password.display());
+        let rewritten = helper_args(args(&[
+            "upgraded_giggle",
+            "https://s",
+            "-s",
+            "/mnt",
+            "-f",
+            "-o",
+            &option,
+        ]));
+        fs::remove_file(&password).unwrap();
+        assert_eq!(
+            rewritten.unwrap(),
+            args(&[
+                "upgraded_giggle",
+                "--server",
+                "https://s",
+                "--password",
+                "secret",
+                "mount",
+                "/mnt",
+            ])
+        );
+    }
+
+    #[test]
+    fn helper_args_needs_server_and_mountpoint() {
+        assert!(helper_args(args(&["upgraded_giggle", "https://s"])).is_err());
+        assert!(helper_args(args(&["upgraded_giggle", "https://s", "/mnt", "/extra"])).is_err());
+        assert!(helper_args(args(&["upgraded_giggle", "https://s", "/mnt", "-o"])).is_err());
+        let missing = "password_file=/nonexistent/password";
+        assert!(helper_args(args(&[
+            "upgraded_giggle",
+            "https://s",
+            "/mnt",
+            "-o",
+            missing
+        ]))
+        .is_err());
+    }
 }
@@ -0,0 +1,429 @@
+//! What fuse_mt leaves out: file locks, and inode numbers.
+//!
+//! fuse_mt does not pass lock requests on, so the filesystem is wrapped in `Mount`, which
+//! answers them from `Locks` and hands everything else to fuse_mt. On the way it notes which
+//! inodes open handles refer to, for the poller to tell the kernel about changes to them.
+
+use crate::asyncapi::runtime;
+use crate::handle::{DirectoryHandles, Handles};
+use crate::lock::Locks;
+use crate::notify::{Inodes, Poller};
+use crate::SeafileFS;
+use fuse_mt::FuseMT;
+use fuser::consts::{FUSE_FLOCK_LOCKS, FUSE_POSIX_LOCKS};
+use fuser::{KernelConfig, ReplyEmpty, ReplyLock, Request, TimeOrNow};
+use libc::{c_int, EAGAIN, EINTR, EINVAL, EIO, F_RDLCK, F_UNLCK, F_WRLCK};
+use log::debug;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::{task, time};
+
+/// How often a blocking lock request retries while the file is locked by someone else
+const LOCK_RETRY: Duration = Duration::from_secs(1);
+/// How many times it retries before giving up with EINTR, as if interrupted
+const LOCK_ATTEMPTS: u32 = 3600;
+
+pub struct Mount {
+    inner: FuseMT<SeafileFS>,
+    locks: Arc<Locks>,
+    handles: Arc<Handles>,
+    directory_handles: Arc<DirectoryHandles>,
+    inodes: Arc<Inodes>,
+    poller: Option<Poller>,
+}
+
+impl Mount {
+    pub fn new(filesystem: SeafileFS, threads: usize) -> Self {
+        let locks = Arc::new(filesystem.locks());
+        let inodes = Arc::new(Inodes::new());
+        Self {
+            handles: filesystem.handles.clone(),
+            directory_handles: filesystem.directory_handles.clone(),
+            poller: filesystem.poller(inodes.clone()),
+            inner: FuseMT::new(filesystem, threads),
+            locks,
+            inodes,
+        }
+    }
+
+    /// The poller for changes, to be started once the session is up; `None` if polling is
+    /// disabled or it was taken already.
+    pub fn poller(&mut self) -> Option<Poller> {
+        self.poller.take()
+    }
+
+    /// Note what the file handle `fh` open on `ino` refers to.
+    fn learn_file(&self, ino: u64, fh: u64) {
+        if let Some(location) = self.handles.location(fh) {
+            self.inodes
+                .learn(ino, &location.library_id, &location.path(), false);
+        }
+    }
+}
+
+impl fuser::Filesystem for Mount {
+    fn init(&mut self, req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        if let Err(unsupported) = config.add_capabilities(FUSE_POSIX_LOCKS | FUSE_FLOCK_LOCKS) {
+            debug!(
+                "kernel does not support lock capabilities {:#x}",
+                unsupported
+            );
+        }
+        self.inner.init(req, config)
+    }
+
+    fn destroy(&mut self) {
+        self.locks.release_all();
+        self.inner.destroy()
+    }
+
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.locks.release(fh);
+        self.inner
+            .release(req, ino, fh, flags, lock_owner, flush, reply)
+    }
+
+    fn getlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        debug!("getlk {:#x} {} {:#x} {}", ino, fh, lock_owner, typ);
+        let locks = self.locks.clone();
+        // The server is asked without holding up the session thread
+        runtime().spawn_blocking(move || match locks.conflicts(fh, lock_owner) {
+            // The holder may be on another machine; report the whole file locked, by nobody local
+            Ok(true) => reply.locked(0, i64::MAX as u64, F_WRLCK, 0),
+            Ok(false) => reply.locked(start, end, F_UNLCK, pid),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn setlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        typ: i32,
+        _pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        debug!(
+            "setlk {:#x} {} {:#x} {} {}",
+            ino, fh, lock_owner, typ, sleep
+        );
+        let locks = self.locks.clone();
+        let attempt = move || match typ {
+            F_WRLCK => locks.lock(fh, lock_owner),
+            F_RDLCK => match locks.conflicts(fh, lock_owner)? {
+                true => Err(EAGAIN),
+                false => Ok(()),
+            },
+            F_UNLCK => locks.unlock(fh, lock_owner),
+            _ => Err(EINVAL),
+        };
+        let handles = self.handles.clone();
+        // Ask the server, and wait, without holding up the session thread, which other
+        // requests need
+        runtime().spawn(async move {
+            for _ in 0..LOCK_ATTEMPTS {
+                let result = match task::spawn_blocking(attempt.clone()).await {
+                    Ok(result) => result,
+                    Err(_) => Err(EIO),
+                };
+                match result {
+                    Ok(()) => return reply.ok(),
+                    Err(EAGAIN) if sleep => time::sleep(LOCK_RETRY).await,
+                    Err(e) => return reply.error(e),
+                }
+                // fuser does not pass interrupts on, but a process killed while waiting closes
+                // the file
+                if handles.location(fh).is_none() {
+                    debug!("setlk {} given up, released", fh);
+                    return reply.error(EINTR);
+                }
+            }
+            reply.error(EINTR)
+        });
+    }
+
+    // Everything else goes to fuse_mt as it is
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEntry) {
+        self.inner.lookup(req, parent, name, reply)
+    }
+
+    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.inodes.forget(ino);
+        self.inner.forget(req, ino, nlookup)
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, fh: Option<u64>, reply: fuser::ReplyAttr) {
+        if let Some(fh) = fh {
+            self.learn_file(ino, fh);
+        }
+        self.inner.getattr(req, ino, fh, reply)
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: fuser::ReplyAttr,
+    ) {
+        self.inner.setattr(
+            req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
+            flags, reply,
+        )
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        self.inner.readlink(req, ino, reply)
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        self.inner
+            .mknod(req, parent, name, mode, umask, rdev, reply)
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        self.inner.mkdir(req, parent, name, mode, umask, reply)
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.inner.unlink(req, parent, name, reply)
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.inner.rmdir(req, parent, name, reply)
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: fuser::ReplyEntry,
+    ) {
+        self.inner.symlink(req, parent, name, link, reply)
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.inner
+            .rename(req, parent, name, newparent, newname, flags, reply)
+    }
+
+    fn link(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        self.inner.link(req, ino, newparent, newname, reply)
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        self.inner.open(req, ino, flags, reply)
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        self.learn_file(ino, fh);
+        self.inner
+            .read(req, ino, fh, offset, size, flags, lock_owner, reply)
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
+    ) {
+        self.learn_file(ino, fh);
+        self.inner.write(
+            req,
+            ino,
+            fh,
+            offset,
+            data,
+            write_flags,
+            flags,
+            lock_owner,
+            reply,
+        )
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        self.inner.flush(req, ino, fh, lock_owner, reply)
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.inner.fsync(req, ino, fh, datasync, reply)
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        self.inner.opendir(req, ino, flags, reply)
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: fuser::ReplyDirectory,
+    ) {
+        if let Some((library_id, path)) = self.directory_handles.get(fh) {
+            self.inodes.learn(ino, &library_id, &path, true);
+        }
+        self.inner.readdir(req, ino, fh, offset, reply)
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        self.inner.releasedir(req, ino, fh, flags, reply)
+    }
+
+    fn fsyncdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.inner.fsyncdir(req, ino, fh, datasync, reply)
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
+        self.inner.statfs(req, ino, reply)
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.inner
+            .setxattr(req, ino, name, value, flags, position, reply)
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        self.inner.getxattr(req, ino, name, size, reply)
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
+        self.inner.listxattr(req, ino, size, reply)
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.inner.removexattr(req, ino, name, reply)
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        self.inner.access(req, ino, mask, reply)
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        self.inner
+            .create(req, parent, name, mode, umask, flags, reply)
+    }
+}
@@ -0,0 +1,312 @@
+//! Noticing changes made to libraries elsewhere.
+//!
+//! Every poll interval the library list is fetched afresh. Libraries whose head commit moved
+//! have the directories known to the mount listed again; whatever differs is dropped from the
+//! caches and the kernel is told to forget it too. File contents need no dropping: the block
+//! cache is keyed by content id, so a changed file is read afresh under its new id.
+
+use crate::asyncapi::{runtime, AsyncSeafileAPI};
+use crate::cache::TtlCache;
+use crate::pin::Pins;
+use crate::seafileapi::{Commit, Library, LibraryEntry, SeafileAPI};
+use crate::{DirectoryCache, HistoryCache, TrashCache};
+use fuser::{Notifier, FUSE_ROOT_ID};
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::time;
+
+/// What the kernel knows an inode as.
+#[derive(Debug, Clone)]
+struct Inode {
+    library_id: String,
+    /// Path within the library
+    path: PathBuf,
+    is_dir: bool,
+}
+
+/// Inode numbers of the files and directories seen in use, so the kernel can be told about
+/// them. Only inodes read or listed through a handle are known.
+#[derive(Debug, Default)]
+pub struct Inodes {
+    known: RwLock<HashMap<u64, Inode>>,
+}
+
+impl Inodes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn learn(&self, ino: u64, library_id: &str, path: &Path, is_dir: bool) {
+        let mut known = self.known.write().unwrap();
+        if known.get(&ino).is_none_or(|inode| inode.path != path) {
+            known.insert(
+                ino,
+                Inode {
+                    library_id: library_id.to_string(),
+                    path: path.to_path_buf(),
+                    is_dir,
+                },
+            );
+        }
+    }
+
+    pub fn forget(&self, ino: u64) {
+        self.known.write().unwrap().remove(&ino);
+    }
+
+    fn find(&self, library_id: &str, path: &Path) -> Option<u64> {
+        let known = self.known.read().unwrap();
+        known
+            .iter()
+            .find(|(_, inode)| inode.library_id == library_id && inode.path == path)
+            .map(|(ino, _)| *ino)
+    }
+
+    /// Known directories in the library `library_id`.
+    fn directories(&self, library_id: &str) -> Vec<PathBuf> {
+        let known = self.known.read().unwrap();
+        known
+            .values()
+            .filter(|inode| inode.is_dir && inode.library_id == library_id)
+            .map(|inode| inode.path.clone())
+            .collect()
+    }
+
+    /// Known inodes directly inside `dir`.
+    fn children(&self, library_id: &str, dir: &Path) -> Vec<u64> {
+        let known = self.known.read().unwrap();
+        known
+            .iter()
+            .filter(|(_, inode)| inode.library_id == library_id && inode.path.parent() == Some(dir))
+            .map(|(ino, _)| *ino)
+            .collect()
+    }
+}
+
+/// Checks libraries for changes on a thread of its own.
+pub struct Poller {
+    pub(crate) api: Arc<SeafileAPI>,
+    pub(crate) directories: Arc<DirectoryCache>,
+    pub(crate) history: Arc<HistoryCache>,
+    pub(crate) commits: Arc<TtlCache<String, Arc<Vec<Commit>>>>,
+    pub(crate) trash: Arc<TrashCache>,
+    pub(crate) pins: Arc<Pins>,
+    pub(crate) inodes: Arc<Inodes>,
+    pub(crate) interval: Duration,
+}
+
+/// Whether `new` has changes over `old`. Servers that do not report head commits leave the
+/// modification time to go by.
+fn library_changed(old: &Library, new: &Library) -> bool {
+    old.head_commit_id != new.head_commit_id || old.mtime != new.mtime
+}
+
+/// Names of the entries that differ between two listings of a directory.
+fn changed_names(old: &[LibraryEntry], new: &[LibraryEntry]) -> HashSet<String> {
+    let key = |entry: &LibraryEntry| {
+        (
+            entry.name.clone(),
+            entry.id.clone(),
+            entry.mtime,
+            entry.size,
+        )
+    };
+    let old = old.iter().map(key).collect::<HashSet<_>>();
+    let new = new.iter().map(key).collect::<HashSet<_>>();
+    old.symmetric_difference(&new)
+        .map(|(name, ..)| name.clone())
+        .collect()
+}
+
+impl Poller {
+    /// Start polling in a task on the runtime, telling the kernel of changes through
+    /// `notifier`.
+    pub fn spawn(self, notifier: Notifier) {
+        runtime().spawn(self.run(notifier));
+    }
+
+    async fn run(self, notifier: Notifier) {
+        let api = self.api.async_api().clone();
+        let mut libraries = api.get_libraries().await.unwrap_or_default();
+        // Listings as last seen, to tell what changed in them
+        let mut seen = HashMap::new();
+        loop {
+            time::sleep(self.interval).await;
+            let fresh = match api.refresh_libraries().await {
+                Ok(fresh) => fresh,
+                Err(e) => {
+                    debug!("ERROR: poll {}", e);
+                    continue;
+                }
+            };
+            for library in &fresh {
+                match libraries.iter().find(|old| old.id == library.id) {
+                    Some(old) if library_changed(old, library) => {
+                        debug!("library {:?} changed", library.name);
+                        self.refresh_library(&api, library, &mut seen, &notifier)
+                            .await;
+                        if self.pins.libraries().contains(&library.id) {
+                            self.pins
+                                .spawn_sync(self.api.clone(), vec![library.id.clone()]);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            let names = |libraries: &[Library]| {
+                libraries
+                    .iter()
+                    .map(|library| library.name.clone())
+                    .collect::<HashSet<_>>()
+            };
+            let (old_names, new_names) = (names(&libraries), names(&fresh));
+            if old_names != new_names {
+                debug!("libraries changed");
+                for name in old_names.symmetric_difference(&new_names) {
+                    self.invalidate_entry(&notifier, FUSE_ROOT_ID, name);
+                }
+                self.invalidate_inode(&notifier, FUSE_ROOT_ID);
+            }
+            libraries = fresh;
+        }
+    }
+
+    /// Drop what changed in `library` from the caches and the kernel's.
+    async fn refresh_library(
+        &self,
+        api: &AsyncSeafileAPI,
+        library: &Library,
+        seen: &mut HashMap<(String, PathBuf), Arc<Vec<LibraryEntry>>>,
+        notifier: &Notifier,
+    ) {
+        self.commits.remove(&library.id);
+        self.trash.remove(&library.id);
+        self.history
+            .retain(|(library_id, _)| *library_id != library.id);
+
+        let mut dirs = self.inodes.directories(&library.id);
+        dirs.extend(
+            self.directories
+                .keys()
+                .into_iter()
+                .filter(|(library_id, _)| *library_id == library.id)
+                .map(|(_, dir)| dir),
+        );
+        dirs.sort();
+        dirs.dedup();
+        for dir in dirs {
+            let key = (library.id.clone(), dir);
+            let cached = self.directories.get_stale(&key);
+            let old = cached.clone().or_else(|| seen.get(&key).cloned());
+            let ino = self.inodes.find(&library.id, &key.1);
+            let new = match api.get_library_content(&library.id, &key.1).await {
+                Ok(entries) => Arc::new(entries),
+                Err(e) => {
+                    // Most likely gone, along with everything in it
+                    debug!("ERROR: poll {:?} {}", key.1, e);
+                    self.directories.remove(&key);
+                    seen.remove(&key);
+                    if let Some(ino) = ino {
+                        self.invalidate_inode(notifier, ino);
+                    }
+                    continue;
+                }
+            };
+            match old {
+                Some(old) => {
+                    let changed = changed_names(&old, &new);
+                    if changed.is_empty() {
+                        seen.insert(key, new);
+                        continue;
+                    }
+                    debug!("changed in {:?}: {:?}", key.1, changed);
+                    for name in &changed {
+                        let path = key.1.join(name);
+                        if let Some(child) = self.inodes.find(&library.id, &path) {
+                            self.invalidate_inode(notifier, child);
+                        }
+                        if let Some(ino) = ino {
+                            self.invalidate_entry(notifier, ino, name);
+                        }
+                    }
+                }
+                // Nothing to compare with, so anything in it may have changed
+                None => {
+                    for child in self.inodes.children(&library.id, &key.1) {
+                        self.invalidate_inode(notifier, child);
+                    }
+                }
+            }
+            if let Some(ino) = ino {
+                self.invalidate_inode(notifier, ino);
+            }
+            if cached.is_some() {
+                self.directories.insert(key.clone(), new.clone());
+            }
+            seen.insert(key, new);
+        }
+    }
+
+    fn invalidate_inode(&self, notifier: &Notifier, ino: u64) {
+        if let Err(e) = notifier.inval_inode(ino, 0, 0) {
+            debug!("ERROR: inval_inode({}) {}", ino, e);
+        }
+    }
+
+    fn invalidate_entry(&self, notifier: &Notifier, parent: u64, name: &str) {
+        if let Err(e) = notifier.inval_entry(parent, OsStr::new(name)) {
+            debug!("ERROR: inval_entry({}, {:?}) {}", parent, name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, id: &str, mtime: u64, size: u64) -> LibraryEntry {
+        LibraryEntry {
+            id: id.to_string(),
+            mtime,
+            size,
+            name: name.to_string(),
+            permission: "rw".to_string(),
+            entry_type: "file".to_string(),
+            modifier_email: String::new(),
+            modifier_name: String::new(),
+            is_locked: false,
+            locked_by_me: false,
+            lock_owner: String::new(),
+        }
+    }
+
+    #[test]
+    fn changed_names_finds_added_removed_and_changed_entries() {
+        let old = [
+            entry("same", "1", 10, 1),
+            entry("removed", "2", 10, 1),
+            entry("rewritten", "3", 10, 1),
+            entry("touched", "4", 10, 1),
+        ];
+        let new = [
+            entry("touched", "4", 11, 1),
+            entry("same", "1", 10, 1),
+            entry("rewritten", "5", 10, 2),
+            entry("added", "6", 12, 1),
+        ];
+        let mut changed = changed_names(&old, &new).into_iter().collect::<Vec<_>>();
+        changed.sort();
+        assert_eq!(changed, vec!["added", "removed", "rewritten", "touched"]);
+    }
+
+    #[test]
+    fn changed_names_of_equal_listings_is_empty() {
+        let listing = [entry("a", "1", 10, 1), entry("b", "2", 10, 1)];
+        assert!(changed_names(&listing, &listing).is_empty());
+        assert!(changed_names(&[], &[]).is_empty());
+    }
+}
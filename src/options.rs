@@ -0,0 +1,470 @@
+use crate::seafileapi::{ClientOptions, Result, Secret};
+use fuser::MountOption;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Options accepted by fstab/`mount.fuse` which only mean something to mount(8) itself.
+static MOUNT_ONLY: [&str; 8] = [
+    "defaults", "auto", "noauto", "user", "nouser", "users", "_netdev", "nofail",
+];
+
+/// What to do when a file changed on the server since it was opened for writing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Upload alongside it as `name (SFConflict user date).ext`, as the Seafile clients do
+    Copy,
+    /// Refuse to upload, failing the flush or close with EIO
+    Fail,
+    /// Upload over it regardless
+    Overwrite,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "copy" => Ok(ConflictPolicy::Copy),
+            "fail" => Ok(ConflictPolicy::Fail),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            _ => Err(format!("unknown conflict policy {:?}", s)),
+        }
+    }
+}
+
+/// Mount options, as given with `-o` on the command line or in fstab.
+///
+/// Options handled by the filesystem itself (`uid=`, `gid=`, `umask=`, `fmask=`, `dmask=`,
+/// `owner_map=`, `threads=`, `cache_size=`, `readahead=`, `metadata_ttl=`, `conflict=`, `poll_interval=`,
+/// `journal=`, `pins=`, `server_locks=`), and those of the connection to the server (`connect_timeout=`,
+/// `read_timeout=`, `pool_idle_timeout=`, `pool_size=`, `retries=`, `retry_delay=`, `ca_file=`,
+/// `client_cert=`, `client_key=`, `pin_sha256=`, `insecure`, `proxy=`, `proxy_user=`,
+/// `proxy_password=`, `no_proxy=`, `header=`, `user_agent=`), are parsed out; everything else is
+/// passed through to FUSE and the kernel.
+#[derive(Clone, Debug)]
+pub struct MountOptions {
+    pub fuse: Vec<String>,
+    /// Owner of every file, defaults to the user mounting the filesystem
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub umask: Option<u16>,
+    /// Umask for regular files, overrides `umask`
+    pub fmask: Option<u16>,
+    /// Umask for directories, overrides `umask`
+    pub dmask: Option<u16>,
+    /// Local owner for libraries owned by a Seafile user, keyed by email
+    pub owners: HashMap<String, (u32, u32)>,
+    /// Worker threads for reads and writes
+    pub threads: usize,
+    /// Bytes of file contents to keep in memory
+    pub cache_size: u64,
+    /// Bytes to fetch ahead of sequential reads, 0 to fetch only what is read
+    pub readahead: u64,
+    /// How long directory listings are reused for
+    pub metadata_ttl: Duration,
+    /// What to do when writing back a file someone else changed meanwhile
+    pub conflict: ConflictPolicy,
+    /// How often libraries are checked for changes made elsewhere, if at all
+    pub poll_interval: Option<Duration>,
+    /// Directory of the write-back journal, if not the default
+    pub journal: Option<PathBuf>,
+    /// Directory of pinned files and directories, if not the default
+    pub pins: Option<PathBuf>,
+    /// Whether file locks are taken on the server, or only in the mount
+    pub server_locks: bool,
+    /// How to talk to the server
+    pub client: ClientOptions,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            fuse: Vec::new(),
+            uid: None,
+            gid: None,
+            umask: None,
+            fmask: None,
+            dmask: None,
+            owners: HashMap::new(),
+            threads: 4,
+            cache_size: 256 << 20,
+            readahead: 4 << 20,
+            metadata_ttl: Duration::from_secs(5),
+            conflict: ConflictPolicy::Copy,
+            poll_interval: Some(Duration::from_secs(30)),
+            journal: None,
+            pins: None,
+            server_locks: true,
+            client: ClientOptions::default(),
+        }
+    }
+}
+
+impl MountOptions {
+    /// Parse `-o` values, each of which may be a comma separated list.
+    pub fn parse<S: AsRef<str>>(options: &[S]) -> Result<MountOptions> {
+        let mut result = MountOptions::default();
+        for option in options.iter().flat_map(|o| o.as_ref().split(',')) {
+            let (key, value) = match option.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (option, None),
+            };
+            match (key, value) {
+                ("", None) => {}
+                ("uid", Some(v)) => result.uid = Some(v.parse()?),
+                ("gid", Some(v)) => result.gid = Some(v.parse()?),
+                ("umask", Some(v)) => result.umask = Some(u16::from_str_radix(v, 8)?),
+                ("fmask", Some(v)) => result.fmask = Some(u16::from_str_radix(v, 8)?),
+                ("dmask", Some(v)) => result.dmask = Some(u16::from_str_radix(v, 8)?),
+                ("owner_map", Some(v)) => result.owners = read_owner_map(Path::new(v))?,
+                ("threads", Some(v)) => result.threads = v.parse()?,
+                // In MiB
+                ("cache_size", Some(v)) => result.cache_size = v.parse::<u64>()? << 20,
+                // In MiB, 0 to disable
+                ("readahead", Some(v)) => result.readahead = v.parse::<u64>()? << 20,
+                // In seconds
+                ("metadata_ttl", Some(v)) => result.metadata_ttl = Duration::from_secs(v.parse()?),
+                ("conflict", Some(v)) => result.conflict = v.parse()?,
+                // In seconds, 0 to disable
+                ("poll_interval", Some(v)) => {
+                    result.poll_interval = match v.parse()? {
+                        0 => None,
+                        secs => Some(Duration::from_secs(secs)),
+                    }
+                }
+                ("journal", Some(v)) => result.journal = Some(PathBuf::from(v)),
+                ("pins", Some(v)) => result.pins = Some(PathBuf::from(v)),
+                ("server_locks", Some("on")) => result.server_locks = true,
+                ("server_locks", Some("off")) => result.server_locks = false,
+                ("server_locks", Some(v)) => {
+                    return Err(format!("server_locks is on or off, not {:?}", v).into())
+                }
+                // In seconds
+                ("connect_timeout", Some(v)) => {
+                    result.client.connect_timeout = Duration::from_secs(v.parse()?)
+                }
+                // In seconds, 0 to wait forever
+                ("read_timeout", Some(v)) => {
+                    result.client.read_timeout = match v.parse()? {
+                        0 => None,
+                        secs => Some(Duration::from_secs(secs)),
+                    }
+                }
+                // In seconds
+                ("pool_idle_timeout", Some(v)) => {
+                    result.client.pool_idle_timeout = Duration::from_secs(v.parse()?)
+                }
+                ("pool_size", Some(v)) => result.client.pool_size = v.parse()?,
+                ("retries", Some(v)) => result.client.retries = v.parse()?,
+                // In milliseconds
+                ("retry_delay", Some(v)) => {
+                    result.client.retry_delay = Duration::from_millis(v.parse()?)
+                }
+                ("ca_file", Some(v)) => result.client.ca_file = Some(PathBuf::from(v)),
+                ("client_cert", Some(v)) => result.client.client_cert = Some(PathBuf::from(v)),
+                ("client_key", Some(v)) => result.client.client_key = Some(PathBuf::from(v)),
+                // May be given more than once
+                ("pin_sha256", Some(v)) => result.client.pinned.push(parse_fingerprint(v)?),
+                ("insecure", None) => result.client.insecure = true,
+                ("proxy", Some(v)) => result.client.proxy = Some(v.to_string()),
+                ("proxy_user", Some(v)) => result.client.proxy_user = Some(v.to_string()),
+                ("proxy_password", Some(v)) => {
+                    result.client.proxy_password = Some(Secret(v.to_string()))
+                }
+                // One host or domain each, may be given more than once
+                ("no_proxy", Some(v)) => result.client.no_proxy.push(v.to_string()),
+                // `<name>:<value>`, may be given more than once
+                ("header", Some(v)) => {
+                    let (name, value) = v
+                        .split_once(':')
+                        .ok_or_else(|| format!("header {:?} is not <name>:<value>", v))?;
+                    result
+                        .client
+                        .headers
+                        .push((name.trim().to_string(), Secret(value.trim().to_string())));
+                }
+                ("user_agent", Some(v)) => result.client.user_agent = v.to_string(),
+                (
+                    "uid" | "gid" | "umask" | "fmask" | "dmask" | "owner_map" | "threads"
+                    | "cache_size" | "readahead" | "metadata_ttl" | "conflict" | "poll_interval"
+                    | "journal" | "pins" | "server_locks" | "connect_timeout" | "read_timeout"
+                    | "pool_idle_timeout" | "pool_size" | "retries" | "retry_delay" | "ca_file"
+                    | "client_cert" | "client_key" | "pin_sha256" | "proxy" | "proxy_user"
+                    | "proxy_password" | "no_proxy" | "header" | "user_agent",
+                    None,
+                ) => return Err(format!("mount option {} needs a value", key).into()),
+                (key, None) if MOUNT_ONLY.contains(&key) => {}
+                _ => result.fuse.push(option.to_string()),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Local uid and gid for something owned by the Seafile user `owner`.
+    pub fn owner(&self, owner: Option<&str>) -> (u32, u32) {
+        if let Some(ids) = owner.and_then(|o| self.owners.get(o)) {
+            return *ids;
+        }
+        (
+            self.uid.unwrap_or_else(|| unsafe { libc::getuid() }),
+            self.gid.unwrap_or_else(|| unsafe { libc::getgid() }),
+        )
+    }
+
+    /// Umask for files (or directories, if `directory`).
+    pub fn mask(&self, directory: bool) -> u16 {
+        match directory {
+            true => self.dmask,
+            false => self.fmask,
+        }
+        .or(self.umask)
+        .unwrap_or(0)
+    }
+
+    /// Options for `fuser::Session`, with defaults for anything not given explicitly.
+    pub fn fuse_options(&self) -> Vec<MountOption> {
+        let given = |prefix: &str| self.fuse.iter().any(|o| o.starts_with(prefix));
+        let mut options = Vec::new();
+        if !self.fuse.iter().any(|o| o == "ro" || o == "rw") {
+            options.push("rw".to_string());
+        }
+        if !given("fsname=") {
+            options.push("fsname=seafile".to_string());
+        }
+        if !given("subtype=") {
+            options.push("subtype=seafile".to_string());
+        }
+        options.extend(self.fuse.iter().cloned());
+        options
+            .into_iter()
+            .map(|o| match o.as_str() {
+                "auto_unmount" => MountOption::AutoUnmount,
+                "allow_other" => MountOption::AllowOther,
+                "allow_root" => MountOption::AllowRoot,
+                "default_permissions" => MountOption::DefaultPermissions,
+                "dev" => MountOption::Dev,
+                "nodev" => MountOption::NoDev,
+                "suid" => MountOption::Suid,
+                "nosuid" => MountOption::NoSuid,
+                "ro" => MountOption::RO,
+                "rw" => MountOption::RW,
+                "exec" => MountOption::Exec,
+                "noexec" => MountOption::NoExec,
+                "atime" => MountOption::Atime,
+                "noatime" => MountOption::NoAtime,
+                "dirsync" => MountOption::DirSync,
+                "sync" => MountOption::Sync,
+                "async" => MountOption::Async,
+                _ => match o.split_once('=') {
+                    Some(("fsname", name)) => MountOption::FSName(name.to_string()),
+                    Some(("subtype", name)) => MountOption::Subtype(name.to_string()),
+                    _ => MountOption::CUSTOM(o),
+                },
+            })
+            .collect()
+    }
+}
+
+/// Directory for the local state `what` of `username` on `server`: under `$XDG_STATE_HOME`,
+/// or `~/.local/state`.
+pub fn state_dir(what: &str, server: &str, username: &str) -> PathBuf {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .unwrap_or_else(std::env::temp_dir);
+    let host = server
+        .split("://")
+        .last()
+        .unwrap_or(server)
+        .trim_end_matches('/')
+        .replace('/', "_");
+    state
+        .join("upgraded_giggle")
+        .join(what)
+        .join(format!("{}@{}", username, host))
+}
+
+/// A SHA-256 fingerprint as lowercase hex, from hex with or without colons, as
+/// `openssl x509 -fingerprint -sha256` prints them.
+fn parse_fingerprint(fingerprint: &str) -> Result<String> {
+    let hex = fingerprint.replace(':', "").to_ascii_lowercase();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("{:?} is not a SHA-256 fingerprint", fingerprint).into());
+    }
+    Ok(hex)
+}
+
+/// Read a table mapping Seafile users to local users, one per line:
+///
+/// ```text
+/// # email                user[:group]
+/// alice@example.com      alice
+/// bob@example.com        1001:100
+/// ```
+///
+/// Users and groups may be names or numeric ids; the group defaults to the user's primary group.
+fn read_owner_map(path: &Path) -> Result<HashMap<String, (u32, u32)>> {
+    let mut owners = HashMap::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (email, local) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("{:?}: no local user in {:?}", path, line))?;
+        let (user, group) = match local.trim().split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (local.trim(), None),
+        };
+        let (uid, primary_gid) = lookup_user(user)?;
+        let gid = match group {
+            Some(group) => lookup_group(group)?,
+            None => primary_gid,
+        };
+        owners.insert(email.to_string(), (uid, gid));
+    }
+    Ok(owners)
+}
+
+fn lookup_user(user: &str) -> Result<(u32, u32)> {
+    let name = CString::new(user)?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if !passwd.is_null() {
+        return Ok(unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) });
+    }
+    let uid = user
+        .parse()
+        .map_err(|_| format!("no such user {:?}", user))?;
+    let passwd = unsafe { libc::getpwuid(uid) };
+    match passwd.is_null() {
+        true => Ok((uid, uid)),
+        false => Ok((uid, unsafe { (*passwd).pw_gid })),
+    }
+}
+
+fn lookup_group(group: &str) -> Result<u32> {
+    let name = CString::new(group)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if !entry.is_null() {
+        return Ok(unsafe { (*entry).gr_gid });
+    }
+    Ok(group
+        .parse()
+        .map_err(|_| format!("no such group {:?}", group))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_passes_other_options_to_fuse() {
+        let options = MountOptions::parse(&["uid=1000,gid=100,allow_other", "umask=022"]).unwrap();
+        assert_eq!(options.uid, Some(1000));
+        assert_eq!(options.gid, Some(100));
+        assert_eq!(options.umask, Some(0o022));
+        assert_eq!(options.fuse, vec!["allow_other"]);
+    }
+
+    #[test]
+    fn parse_drops_mount_only_options() {
+        let options = MountOptions::parse(&["defaults,_netdev,nofail,,noatime"]).unwrap();
+        assert_eq!(options.fuse, vec!["noatime"]);
+    }
+
+    #[test]
+    fn parse_rejects_bad_values() {
+        assert!(MountOptions::parse(&["uid"]).is_err());
+        assert!(MountOptions::parse(&["uid=me"]).is_err());
+        assert!(MountOptions::parse(&["umask=999"]).is_err());
+    }
+
+    #[test]
+    fn fuse_options_default_unless_given() {
+        let options = MountOptions::parse(&["ro,fsname=mine"]).unwrap();
+        let fuse = options.fuse_options();
+        assert!(fuse.contains(&MountOption::RO));
+        assert!(!fuse.contains(&MountOption::RW));
+        assert!(fuse.contains(&MountOption::FSName("mine".to_string())));
+        assert!(!fuse.contains(&MountOption::FSName("seafile".to_string())));
+        assert!(fuse.contains(&MountOption::Subtype("seafile".to_string())));
+    }
+
+    #[test]
+    fn mask_prefers_specific_masks() {
+        let options = MountOptions::parse(&["umask=022,fmask=133"]).unwrap();
+        assert_eq!(options.mask(false), 0o133);
+        assert_eq!(options.mask(true), 0o022);
+        assert_eq!(MountOptions::default().mask(true), 0);
+    }
+
+    #[test]
+    fn parse_conflict_policy() {
+        let options = MountOptions::parse(&["conflict=overwrite"]).unwrap();
+        assert_eq!(options.conflict, ConflictPolicy::Overwrite);
+        assert_eq!(MountOptions::default().conflict, ConflictPolicy::Copy);
+        assert!(MountOptions::parse(&["conflict=merge"]).is_err());
+    }
+
+    #[test]
+    fn parse_server_locks() {
+        assert!(MountOptions::default().server_locks);
+        let options = MountOptions::parse(&["server_locks=off"]).unwrap();
+        assert!(!options.server_locks);
+        assert!(MountOptions::parse(&["server_locks=maybe"]).is_err());
+    }
+
+    #[test]
+    fn parse_zero_poll_interval_disables_polling() {
+        let options = MountOptions::parse(&["poll_interval=0"]).unwrap();
+        assert_eq!(options.poll_interval, None);
+        let options = MountOptions::parse(&["poll_interval=7"]).unwrap();
+        assert_eq!(options.poll_interval, Some(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn parse_connection_options() {
+        let options =
+            MountOptions::parse(&["connect_timeout=3,read_timeout=0,retry_delay=250"]).unwrap();
+        assert_eq!(options.client.connect_timeout, Duration::from_secs(3));
+        assert_eq!(options.client.read_timeout, None);
+        assert_eq!(options.client.retry_delay, Duration::from_millis(250));
+        assert!(MountOptions::parse(&["retries"]).is_err());
+    }
+
+    #[test]
+    fn parse_fingerprint_accepts_openssl_output() {
+        let hex = "ab".repeat(32);
+        let colons = vec!["AB"; 32].join(":");
+        assert_eq!(parse_fingerprint(&hex).unwrap(), hex);
+        assert_eq!(parse_fingerprint(&colons).unwrap(), hex);
+        assert!(parse_fingerprint(&"ab".repeat(31)).is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+        let options = MountOptions::parse(&[format!("pin_sha256={}", colons)]).unwrap();
+        assert_eq!(options.client.pinned, vec![hex]);
+        assert!(MountOptions::parse(&["pin_sha256=abc"]).is_err());
+    }
+
+    #[test]
+    fn parse_repeated_options_accumulate() {
+        let options = MountOptions::parse(&[
+            "no_proxy=a.example,no_proxy=b.example,proxy=http://proxy:3128",
+            "header=X-One: 1,header=X-Two:2",
+        ])
+        .unwrap();
+        assert_eq!(options.client.no_proxy, vec!["a.example", "b.example"]);
+        let headers = options
+            .client
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.0.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(headers, vec![("X-One", "1"), ("X-Two", "2")]);
+        assert!(MountOptions::parse(&["header=no-colon"]).is_err());
+    }
+}
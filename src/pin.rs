@@ -0,0 +1,414 @@
+//! Pinned files and directories, kept on disk so they can be read offline.
+//!
+//! The pinned paths are listed in `pinned.json`. Syncing fetches the libraries they are in into
+//! `libraries/`, the listings of every pinned directory, and of the directories leading to each
+//! pinned path, into `listings/`, and the contents of every pinned file into `content/`, keyed
+//! by file id. Whatever is no longer
+//! pinned is dropped as the library is synced.
+//!
+//! Everything is kept on disk, and the pinned paths read again whenever `pinned.json` changes,
+//! so the mount and the command line can share a pin directory.
+
+use crate::asyncapi::runtime;
+use crate::options::state_dir;
+use crate::seafileapi::{self, Library, LibraryEntry, SeafileAPI};
+use bytes::Bytes;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tempfile::NamedTempFile;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pin {
+    pub library_id: String,
+    pub path: PathBuf,
+}
+
+/// The pinned paths, as last read or saved.
+#[derive(Debug, Default)]
+struct Manifest {
+    pins: Arc<Vec<Pin>>,
+    /// Modification time and inode of `pinned.json` as of `pins`, if there is one; it is
+    /// replaced whenever saved, so a new inode tells of changes made within the same tick
+    stamp: Option<(SystemTime, u64)>,
+}
+
+#[derive(Debug)]
+pub struct Pins {
+    dir: PathBuf,
+    manifest: Mutex<Manifest>,
+    /// Held while syncing, so that one sync does not drop what another is fetching
+    syncing: Mutex<()>,
+}
+
+/// File name for the listing of `dir`, with `%` and `/` escaped.
+fn listing_name(dir: &Path) -> String {
+    let dir = dir
+        .to_string_lossy()
+        .replace('%', "%25")
+        .replace('/', "%2F");
+    format!("{}.json", dir)
+}
+
+/// A temporary file beside `path`, to be persisted as it once complete. Its name starts with
+/// `.tmp`, unlike any kept file's.
+fn temporary(path: &Path) -> io::Result<NamedTempFile> {
+    NamedTempFile::new_in(path.parent().unwrap_or_else(|| Path::new(".")))
+}
+
+/// Write `contents` to `path` in one go, so readers never see it half written.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = temporary(path)?;
+    file.write_all(contents)?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+impl Pins {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            manifest: Mutex::new(Manifest::default()),
+            syncing: Mutex::new(()),
+        }
+    }
+
+    /// Where pins for `username` on `server` are kept unless told otherwise.
+    pub fn default_dir(server: &str, username: &str) -> PathBuf {
+        state_dir("pins", server, username)
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.dir.join("pinned.json")
+    }
+
+    fn listings_dir(&self, library_id: &str) -> PathBuf {
+        self.dir.join("listings").join(library_id)
+    }
+
+    fn library_path(&self, library_id: &str) -> PathBuf {
+        self.dir
+            .join("libraries")
+            .join(format!("{}.json", library_id))
+    }
+
+    fn content_path(&self, file_id: &str) -> PathBuf {
+        self.dir.join("content").join(file_id)
+    }
+
+    /// The pinned paths.
+    pub fn pins(&self) -> io::Result<Vec<Pin>> {
+        Ok(self.pinned()?.to_vec())
+    }
+
+    /// Modification time and inode of `pinned.json`, if there is one.
+    fn manifest_stamp(&self) -> io::Result<Option<(SystemTime, u64)>> {
+        match fs::metadata(self.manifest_path()) {
+            Ok(metadata) => Ok(Some((metadata.modified()?, metadata.ino()))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The pinned paths, read from disk only if they were changed there, by another process.
+    fn pinned(&self) -> io::Result<Arc<Vec<Pin>>> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let stamp = self.manifest_stamp()?;
+        if manifest.stamp != stamp {
+            manifest.pins = match stamp {
+                Some(_) => Arc::new(serde_json::from_slice(&fs::read(self.manifest_path())?)?),
+                None => Arc::default(),
+            };
+            manifest.stamp = stamp;
+        }
+        Ok(manifest.pins.clone())
+    }
+
+    fn save(&self, pins: Vec<Pin>) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut manifest = self.manifest.lock().unwrap();
+        write_atomically(&self.manifest_path(), &serde_json::to_vec_pretty(&pins)?)?;
+        manifest.stamp = self.manifest_stamp()?;
+        manifest.pins = Arc::new(pins);
+        Ok(())
+    }
+
+    /// Pin `path`. Returns whether it was not pinned already.
+    pub fn pin(&self, library_id: &str, path: &Path) -> io::Result<bool> {
+        let pin = Pin {
+            library_id: library_id.to_string(),
+            path: path.to_path_buf(),
+        };
+        let mut pins = self.pins()?;
+        if pins.contains(&pin) {
+            return Ok(false);
+        }
+        pins.push(pin);
+        self.save(pins)?;
+        Ok(true)
+    }
+
+    /// Unpin `path`. Returns whether it was pinned.
+    pub fn unpin(&self, library_id: &str, path: &Path) -> io::Result<bool> {
+        let mut pins = self.pins()?;
+        let count = pins.len();
+        pins.retain(|pin| !(pin.library_id == library_id && pin.path == path));
+        if pins.len() == count {
+            return Ok(false);
+        }
+        self.save(pins)?;
+        Ok(true)
+    }
+
+    /// Whether `path` is pinned, itself or through a directory it is in.
+    pub fn is_pinned(&self, library_id: &str, path: &Path) -> bool {
+        self.pinned()
+            .unwrap_or_default()
+            .iter()
+            .any(|pin| pin.library_id == library_id && path.starts_with(&pin.path))
+    }
+
+    /// The libraries with anything pinned, as last synced.
+    pub fn kept_libraries(&self) -> Vec<Library> {
+        let dir = match fs::read_dir(self.dir.join("libraries")) {
+            Ok(dir) => dir,
+            Err(_) => return Vec::new(),
+        };
+        dir.filter_map(|file| fs::read(file.ok()?.path()).ok())
+            .filter_map(|json| serde_json::from_slice(&json).ok())
+            .collect()
+    }
+
+    /// The listing of `dir` as last synced, if it is kept.
+    pub fn listing(&self, library_id: &str, dir: &Path) -> Option<Vec<LibraryEntry>> {
+        let path = self.listings_dir(library_id).join(listing_name(dir));
+        let json = fs::read(path).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    /// Whether the contents of the file `file_id` are kept.
+    pub fn is_kept(&self, file_id: &str) -> bool {
+        self.content_path(file_id).exists()
+    }
+
+    /// Read from the kept contents of the file `file_id`, if they are kept.
+    pub fn read(&self, file_id: &str, offset: u64, size: u64) -> Option<io::Result<Bytes>> {
+        let content = File::open(self.content_path(file_id)).ok()?;
+        let mut buffer = vec![0; size as usize];
+        let mut read = 0;
+        while read < buffer.len() {
+            match content.read_at(&mut buffer[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        buffer.truncate(read);
+        Some(Ok(Bytes::from(buffer)))
+    }
+
+    /// Fetch what is pinned in the library `library_id` afresh, and drop whatever is no
+    /// longer needed.
+    pub fn sync(&self, api: &SeafileAPI, library_id: &str) -> seafileapi::Result<()> {
+        let _syncing = self.syncing.lock().unwrap();
+        let pins = self
+            .pins()?
+            .into_iter()
+            .filter(|pin| pin.library_id == library_id)
+            .collect::<Vec<_>>();
+        let library_path = self.library_path(library_id);
+        match pins.is_empty() {
+            true => match fs::remove_file(&library_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+            false => {
+                if let Some(library) = api
+                    .get_libraries()?
+                    .into_iter()
+                    .find(|library| library.id == library_id)
+                {
+                    fs::create_dir_all(self.dir.join("libraries"))?;
+                    write_atomically(&library_path, &serde_json::to_vec(&library)?)?;
+                }
+            }
+        }
+        let listings_dir = self.listings_dir(library_id);
+        fs::create_dir_all(&listings_dir)?;
+        fs::create_dir_all(self.dir.join("content"))?;
+
+        let mut listings = HashSet::new();
+        let mut save_listing = |dir: &Path| -> seafileapi::Result<Vec<LibraryEntry>> {
+            let entries = api.get_library_content(library_id, dir)?;
+            let name = listing_name(dir);
+            write_atomically(&listings_dir.join(&name), &serde_json::to_vec(&entries)?)?;
+            listings.insert(name);
+            Ok(entries)
+        };
+        let mut files = Vec::new();
+        for pin in &pins {
+            debug!("syncing pinned {:?}", pin.path);
+            // The directories leading to it, so it can be found
+            let mut is_dir = pin.path.parent().is_none();
+            for dir in pin
+                .path
+                .ancestors()
+                .skip(1)
+                .collect::<Vec<_>>()
+                .into_iter()
+                .rev()
+            {
+                let entries = save_listing(dir)?;
+                if Some(dir) == pin.path.parent() {
+                    let name = pin.path.file_name().unwrap_or_default().to_string_lossy();
+                    match entries.into_iter().find(|entry| entry.name == name) {
+                        Some(entry) if entry.entry_type == "dir" => is_dir = true,
+                        Some(entry) => files.push((pin.path.clone(), entry)),
+                        None => warn!("pinned {:?} no longer exists", pin.path),
+                    }
+                }
+            }
+            if !is_dir {
+                continue;
+            }
+            let mut dirs = vec![pin.path.clone()];
+            while let Some(dir) = dirs.pop() {
+                for entry in save_listing(&dir)? {
+                    let path = dir.join(&entry.name);
+                    match entry.entry_type.as_str() {
+                        "dir" => dirs.push(path),
+                        _ => files.push((path, entry)),
+                    }
+                }
+            }
+        }
+
+        for (path, entry) in &files {
+            let content_path = self.content_path(&entry.id);
+            if content_path.exists() {
+                continue;
+            }
+            debug!("fetching pinned {:?}", path);
+            let link = api.get_download_link(library_id, path)?;
+            let mut download = api.download_stream(&link)?;
+            let mut content = temporary(&content_path)?;
+            let size = io::copy(&mut download, content.as_file_mut())?;
+            // Whatever is kept is served as the file from then on
+            if size != entry.size {
+                return Err(format!(
+                    "fetching pinned {:?}: got {} bytes of {}",
+                    path, size, entry.size
+                )
+                .into());
+            }
+            content.persist(&content_path).map_err(|e| e.error)?;
+        }
+        info!(
+            "synced {} pinned paths, {} files, in library {}",
+            pins.len(),
+            files.len(),
+            library_id
+        );
+
+        for listing in fs::read_dir(&listings_dir)? {
+            let listing = listing?;
+            if !listings.contains(&*listing.file_name().to_string_lossy()) {
+                fs::remove_file(listing.path())?;
+            }
+        }
+        self.collect_garbage()?;
+        Ok(())
+    }
+
+    /// Sync the libraries `library_ids` in the background, on the runtime's blocking threads.
+    pub(crate) fn spawn_sync(self: &Arc<Self>, api: Arc<SeafileAPI>, library_ids: Vec<String>) {
+        let pins = self.clone();
+        runtime().spawn_blocking(move || {
+            for library_id in library_ids {
+                if let Err(e) = pins.sync(&api, &library_id) {
+                    warn!("syncing pins in library {} failed: {}", library_id, e);
+                }
+            }
+        });
+    }
+
+    /// Libraries with anything pinned.
+    pub(crate) fn libraries(&self) -> Vec<String> {
+        let mut library_ids = self
+            .pins()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|pin| pin.library_id)
+            .collect::<Vec<_>>();
+        library_ids.sort();
+        library_ids.dedup();
+        library_ids
+    }
+
+    /// Remove kept contents no kept listing refers to.
+    fn collect_garbage(&self) -> io::Result<()> {
+        let mut ids = HashSet::new();
+        for pin in self.pins()? {
+            let mut dirs = vec![pin.path.clone()];
+            if let Some(parent) = pin.path.parent() {
+                let name = pin.path.file_name().unwrap_or_default().to_string_lossy();
+                for entry in self.listing(&pin.library_id, parent).unwrap_or_default() {
+                    if entry.name == name && entry.entry_type != "dir" {
+                        ids.insert(entry.id);
+                    }
+                }
+            }
+            while let Some(dir) = dirs.pop() {
+                for entry in self.listing(&pin.library_id, &dir).unwrap_or_default() {
+                    match entry.entry_type.as_str() {
+                        "dir" => dirs.push(dir.join(&entry.name)),
+                        _ => {
+                            ids.insert(entry.id);
+                        }
+                    }
+                }
+            }
+        }
+        for content in fs::read_dir(self.dir.join("content"))? {
+            let content = content?;
+            let name = content.file_name();
+            let name = name.to_string_lossy();
+            // Being fetched, maybe by another process sharing the directory
+            if name.starts_with(".tmp") {
+                continue;
+            }
+            if !ids.contains(&*name) {
+                debug!("dropping unpinned {:?}", content.file_name());
+                fs::remove_file(content.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_made_elsewhere_are_seen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mount = Pins::new(dir.path().to_path_buf());
+        let cli = Pins::new(dir.path().to_path_buf());
+        assert!(!mount.is_pinned("lib", Path::new("/docs/a")));
+        assert!(mount.pin("lib", Path::new("/docs")).unwrap());
+        assert!(!mount.pin("lib", Path::new("/docs")).unwrap());
+        assert!(mount.is_pinned("lib", Path::new("/docs/a")));
+        assert!(!mount.is_pinned("other", Path::new("/docs/a")));
+        assert!(cli.is_pinned("lib", Path::new("/docs/a")));
+        assert!(cli.unpin("lib", Path::new("/docs")).unwrap());
+        assert!(!mount.is_pinned("lib", Path::new("/docs/a")));
+        assert!(mount.pins().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,84 @@
+//! Fetching ahead of sequential reads.
+//!
+//! Readers going through a file in order (media players, `tar`, checksums) ask for a little at
+//! a time, so each read would wait out a round trip. Once reads through a handle follow on from
+//! one another, the blocks past them are fetched into the block cache by tasks on the runtime,
+//! half the `readahead=` window at a time, keeping ahead of the reader.
+
+use crate::asyncapi::runtime;
+use crate::cache::BLOCK_SIZE;
+use crate::handle::Handle;
+use crate::journal::PENDING_ID;
+use crate::seafileapi::LibraryEntry;
+use crate::SeafileFS;
+use log::debug;
+
+impl SeafileFS {
+    /// Note a read of `size` bytes at `offset` from `file` through `handle`, and start
+    /// fetching what follows if the reads through it are sequential.
+    pub(crate) fn read_ahead(
+        &self,
+        handle: &mut Handle,
+        file: &LibraryEntry,
+        offset: u64,
+        size: u64,
+    ) {
+        let window = self.options.readahead;
+        let end = offset + size;
+        // Reads may arrive a little out of order when served by several threads
+        let sequential = offset > 0
+            && offset + BLOCK_SIZE >= handle.read_end
+            && offset <= handle.read_end + BLOCK_SIZE;
+        handle.read_end = handle.read_end.max(end);
+        if !sequential {
+            handle.read_end = end;
+            handle.read_ahead_end = 0;
+            return;
+        }
+        if window == 0
+            || file.id.starts_with(PENDING_ID)
+            || self.pins.is_kept(&file.id)
+            || handle.read_ahead_end >= (end + window / 2).min(file.size)
+        {
+            return;
+        }
+
+        // The block the read ends in is fetched along with it
+        let from = handle.read_ahead_end.max(end);
+        let to = (end + window).min(file.size);
+        let (first, last) = (from.div_ceil(BLOCK_SIZE), to.saturating_sub(1) / BLOCK_SIZE);
+        if from >= to || first > last {
+            return;
+        }
+        handle.read_ahead_end = to;
+        debug!("read_ahead {:?} blocks {}..={}", handle.path(), first, last);
+
+        let (api, blocks) = (self.api.async_api().clone(), self.blocks.clone());
+        let (library_id, path, file_id) =
+            (handle.library.id.clone(), handle.path(), file.id.clone());
+        runtime().spawn(async move {
+            // Blocks others are fetching are theirs to bring in; nothing waits on this
+            let (_, fetching) = blocks.claim(&file_id, first..=last, false);
+            let Some(fetching) = fetching else {
+                return;
+            };
+            let (from, to) = (*fetching.blocks.start(), *fetching.blocks.end());
+            let download = api.download_file_range(
+                &library_id,
+                &path,
+                &file_id,
+                from * BLOCK_SIZE,
+                (to - from + 1) * BLOCK_SIZE,
+            );
+            match download.await {
+                Ok(mut data) => {
+                    for block in from..=to {
+                        let chunk = data.split_to((BLOCK_SIZE as usize).min(data.len()));
+                        blocks.insert(&file_id, block, chunk);
+                    }
+                }
+                Err(e) => debug!("ERROR: read_ahead({:?}) {}", path, e),
+            }
+        });
+    }
+}
@@ -0,0 +1,171 @@
+//! Turning FUSE paths into the libraries and entries they refer to.
+
+use crate::history::HISTORY_DIR;
+use crate::seafileapi::{self, Commit, CommitEntry, FileRevision, Library, LibraryEntry};
+use crate::snapshots::SNAPSHOTS_DIR;
+use crate::trash::TRASH_DIR;
+use crate::SeafileFS;
+use libc::{c_int, EACCES, EIO, ENETDOWN, ENOENT, ENOTDIR, EPERM, EROFS};
+use log::debug;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+/// Where a FUSE path points.
+#[derive(Debug)]
+pub enum Location {
+    /// The mount root, listing libraries
+    Root,
+    /// The root directory of a library
+    Library(Library),
+    /// A file or directory within a library; `path` is absolute within the library and may not
+    /// exist yet
+    Entry { library: Library, path: PathBuf },
+    /// `<dir>/.seafile-history`, listing the files in `dir`
+    History { library: Library, dir: PathBuf },
+    /// `<dir>/.seafile-history/<name>`, listing the revisions of `file`
+    FileHistory { library: Library, file: PathBuf },
+    /// `<dir>/.seafile-history/<name>/<revision>`, a past revision of `file`
+    Revision {
+        library: Library,
+        file: PathBuf,
+        revision: FileRevision,
+    },
+    /// `/<library>/.snapshots`, listing the commits of the library
+    Snapshots(Library),
+    /// `path` within the library as of `commit`; `entry` is None for the root directory
+    Snapshot {
+        library: Library,
+        commit: Commit,
+        path: PathBuf,
+        entry: Option<CommitEntry>,
+    },
+    /// `/<library>/.trash`, listing entries deleted from the library. The entries themselves
+    /// resolve to `Snapshot`s of the last commit they existed in.
+    Trash(Library),
+}
+
+/// Map an API error onto an errno: missing things are ENOENT, an unreachable server ENETDOWN,
+/// anything else EIO.
+pub fn errno(e: &seafileapi::Error) -> c_int {
+    if seafileapi::is_offline(e) {
+        return ENETDOWN;
+    }
+    match e
+        .downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+        .map(|s| s.as_u16())
+    {
+        Some(404) => ENOENT,
+        Some(403) => EACCES,
+        _ => EIO,
+    }
+}
+
+impl SeafileFS {
+    /// All libraries, sorted by name, with only the first of any sharing a name.
+    pub(crate) fn libraries(&self) -> Result<Vec<Library>, c_int> {
+        let mut libraries = match self.api.get_libraries() {
+            Ok(libraries) => libraries,
+            // Mounted offline, pinned libraries can still be read
+            Err(e) if seafileapi::is_offline(&e) && !self.pins.kept_libraries().is_empty() => {
+                self.pins.kept_libraries()
+            }
+            Err(e) => {
+                debug!("ERROR: get_libraries {}", e);
+                return Err(errno(&e));
+            }
+        };
+        libraries.sort_by(|a, b| a.name.cmp(&b.name));
+        libraries.dedup_by(|a, b| a.name.eq(&b.name));
+        Ok(libraries)
+    }
+
+    /// Resolve a FUSE path. Only the library is looked up; entries are not checked to exist,
+    /// except those leading into history directories, snapshots and the trash.
+    pub(crate) fn resolve(&self, path: &Path) -> Result<Location, c_int> {
+        let mut components = path.components().filter_map(|c| match c {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        });
+        let library_name = match components.next() {
+            Some(name) => name.to_string_lossy(),
+            None => return Ok(Location::Root),
+        };
+        let library = self
+            .libraries()?
+            .into_iter()
+            .find(|library| library.name == library_name)
+            .ok_or_else(|| {
+                debug!("ERROR: no library {:?}", library_name);
+                ENOENT
+            })?;
+        let components = components.collect::<Vec<_>>();
+        if components.first() == Some(&OsStr::new(SNAPSHOTS_DIR)) {
+            return self.resolve_snapshot(library, &components[1..]);
+        }
+        if components.first() == Some(&OsStr::new(TRASH_DIR)) {
+            return self.resolve_trash(library, &components[1..]);
+        }
+        if let Some(i) = components.iter().position(|name| *name == HISTORY_DIR) {
+            let dir = Path::new("/").join(components[..i].iter().collect::<PathBuf>());
+            return self.resolve_history(library, dir, &components[i + 1..]);
+        }
+        let relative_path = Path::new("/").join(components.into_iter().collect::<PathBuf>());
+        match relative_path.parent() {
+            None => Ok(Location::Library(library)),
+            Some(_) => Ok(Location::Entry {
+                library,
+                path: relative_path,
+            }),
+        }
+    }
+
+    /// Resolve `name` in the directory `parent`, for operations creating or removing it. Only
+    /// entries within a library qualify; libraries themselves cannot be created or removed, and
+    /// history, snapshots and the trash are read-only.
+    pub(crate) fn resolve_child(
+        &self,
+        parent: &Path,
+        name: &OsStr,
+    ) -> Result<(Library, PathBuf), c_int> {
+        if parent.parent().is_none() {
+            return Err(EPERM);
+        }
+        let path = parent.join(name);
+        let top = path.iter().nth(2);
+        if path.iter().any(|name| name == HISTORY_DIR)
+            || top == Some(SNAPSHOTS_DIR.as_ref())
+            || top == Some(TRASH_DIR.as_ref())
+        {
+            return Err(EROFS);
+        }
+        match self.resolve(&path)? {
+            Location::Entry { library, path } => Ok((library, path)),
+            _ => Err(EPERM),
+        }
+    }
+
+    /// The entry at `path` within `library`, from the listing of its parent.
+    pub(crate) fn stat(&self, library: &Library, path: &Path) -> Result<LibraryEntry, c_int> {
+        let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+        let name = path.file_name().ok_or(ENOENT)?.to_string_lossy();
+        let entries = self.list(&library.id, parent).map_err(|e| {
+            debug!("ERROR: list({:?}) {}", parent, e);
+            errno(&e)
+        })?;
+        entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .cloned()
+            .ok_or(ENOENT)
+    }
+
+    /// Like `stat`, but fails with ENOTDIR unless the entry is a directory.
+    pub(crate) fn stat_dir(&self, library: &Library, path: &Path) -> Result<LibraryEntry, c_int> {
+        let entry = self.stat(library, path)?;
+        match entry.entry_type.as_str() {
+            "dir" => Ok(entry),
+            _ => Err(ENOTDIR),
+        }
+    }
+}
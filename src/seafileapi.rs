@@ -1,23 +1,301 @@
-use log::debug;
+use crate::asyncapi::{runtime, AsyncSeafileAPI};
+use crate::tls;
 // These require the `serde` dependency.
 use bytes::Bytes;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{NoProxy, Proxy};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use std::sync::Mutex;
+use std::error::Error as _;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// How long requests fail straight away once the server is found unreachable, before it is
+/// pinged again.
+pub(crate) const OFFLINE_RETRY: Duration = Duration::from_secs(10);
+
+/// How long download links are reused for. Seafile lets links asked for with `reuse=1` be used
+/// for an hour.
+pub(crate) const LINK_TTL: Duration = Duration::from_secs(50 * 60);
+
+/// Longest wait between retries, whatever the server asks for.
+pub(crate) const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// A string kept out of debug output, such as a password.
+#[derive(Clone)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"***\"")
+    }
+}
+
+/// How the HTTP client talks to the server.
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    /// How long connecting may take
+    pub connect_timeout: Duration,
+    /// How long to wait for the server at each step of a request, if at all
+    pub read_timeout: Option<Duration>,
+    /// How long idle connections are kept open for reuse
+    pub pool_idle_timeout: Duration,
+    /// Idle connections kept open per host
+    pub pool_size: usize,
+    /// How many times idempotent requests are retried after failures which may pass
+    pub retries: u32,
+    /// Delay before the first retry, doubling with each further one
+    pub retry_delay: Duration,
+    /// Certificate authorities to trust besides the system's, PEM
+    pub ca_file: Option<PathBuf>,
+    /// Certificate to authenticate with, PEM
+    pub client_cert: Option<PathBuf>,
+    /// Key of `client_cert`, PEM in PKCS#8
+    pub client_key: Option<PathBuf>,
+    /// SHA-256 fingerprints, in lowercase hex, one of which the certificate of every host must
+    /// have
+    pub pinned: Vec<String>,
+    /// Accept any certificate for any host. For lab setups only
+    pub insecure: bool,
+    /// Proxy to go through, `http://`, `https://`, `socks5://` or `socks5h://`, instead of those
+    /// in the environment; `none` for no proxy at all
+    pub proxy: Option<String>,
+    /// Credentials for `proxy`, if not in its URL
+    pub proxy_user: Option<String>,
+    pub proxy_password: Option<Secret>,
+    /// Hosts to reach directly rather than through `proxy`
+    pub no_proxy: Vec<String>,
+    /// Headers to send with every request, by name
+    pub headers: Vec<(String, Secret)>,
+    pub user_agent: String,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Some(Duration::from_secs(60)),
+            pool_idle_timeout: Duration::from_secs(90),
+            pool_size: 8,
+            retries: 3,
+            retry_delay: Duration::from_millis(500),
+            ca_file: None,
+            client_cert: None,
+            client_key: None,
+            pinned: Vec::new(),
+            insecure: false,
+            proxy: None,
+            proxy_user: None,
+            proxy_password: None,
+            no_proxy: Vec::new(),
+            headers: Vec::new(),
+            user_agent: concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).to_string(),
+        }
+    }
+}
+
+impl ClientOptions {
+    /// A client with everything but `read_timeout` set, which reqwest's async client only has
+    /// for whole requests, bodies included; `AsyncSeafileAPI` bounds each wait with it instead.
+    pub(crate) fn client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .pool_max_idle_per_host(self.pool_size)
+            .use_preconfigured_tls(tls::config(self)?);
+        match self.proxy.as_deref() {
+            Some("none") => builder = builder.no_proxy(),
+            Some(url) => {
+                let mut proxy = Proxy::all(url)?;
+                if let Some(user) = &self.proxy_user {
+                    let password = self.proxy_password.as_ref().map_or("", |p| p.0.as_str());
+                    proxy = proxy.basic_auth(user, password);
+                }
+                if !self.no_proxy.is_empty() {
+                    proxy = proxy.no_proxy(NoProxy::from_string(&self.no_proxy.join(",")));
+                }
+                builder = builder.proxy(proxy);
+            }
+            None if !self.no_proxy.is_empty() => {
+                return Err("no_proxy needs proxy; the environment has NO_PROXY for its own".into())
+            }
+            None => {}
+        }
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let mut value =
+                HeaderValue::from_str(&value.0).map_err(|e| format!("header {}: {}", name, e))?;
+            value.set_sensitive(true);
+            headers.append(HeaderName::from_bytes(name.as_bytes())?, value);
+        }
+        Ok(builder
+            .default_headers(headers)
+            .user_agent(&self.user_agent)
+            .build()?)
+    }
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+    /// How long to wait before retry number `attempt`, counting from 0: exponential backoff,
+    /// with up to half of it taken off at random so clients do not retry in step.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .retry_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(MAX_RETRY_DELAY);
+        delay.mul_f64(1.0 - fastrand::f64() / 2.0)
+    }
+}
+
+/// Whether a request failing with `status` may succeed if tried again.
+pub(crate) fn is_retryable_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Whether `e` is down to the connection being dropped under a request.
+pub(crate) fn is_connection_reset(e: &reqwest::Error) -> bool {
+    let mut source = e.source();
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<io::Error>() {
+            return matches!(
+                error.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            );
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// How long the server asked to be left alone for, in seconds or until a date.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(seconds) = value.trim().parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+/// The error of connections to a server whose certificate is not one of those pinned.
+#[derive(Debug)]
+pub struct PinMismatch {
+    pub(crate) fingerprint: String,
+}
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "server certificate {} matches none of the pinned fingerprints",
+            self.fingerprint
+        )
+    }
+}
+
+impl std::error::Error for PinMismatch {}
+
+/// The error of requests not made because the server is unreachable.
+#[derive(Debug)]
+pub struct Offline;
+
+impl fmt::Display for Offline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server unreachable")
+    }
+}
+
+impl std::error::Error for Offline {}
+
+/// `Ok` if `result`, the body of a response to a change, is the `"success"` Seafile answers
+/// changes with.
+pub fn success(result: String) -> Result<()> {
+    match result.as_str() {
+        "\"success\"" => Ok(()),
+        _ => Err(format!("unexpected response {}", result).into()),
+    }
+}
+
+/// Whether `e` is down to the server being unreachable, rather than to the request.
+pub fn is_offline(e: &Error) -> bool {
+    offline_error(e.as_ref())
+}
+
+pub(crate) fn offline_error(e: &(dyn std::error::Error + 'static)) -> bool {
+    if e.is::<Offline>() {
+        return true;
+    }
+    if let Some(e) = e.downcast_ref::<io::Error>() {
+        return e.kind() == io::ErrorKind::TimedOut;
+    }
+    match e.downcast_ref::<reqwest::Error>() {
+        Some(e) => (e.is_connect() || e.is_timeout()) && !tls::is_certificate_error(e),
+        None => false,
+    }
+}
+
+/// Whether the fileserver refused a download link, which it does once the link expired.
+pub(crate) fn is_link_refused(e: &(dyn std::error::Error + 'static)) -> bool {
+    match e
+        .downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+    {
+        Some(status) => {
+            status == reqwest::StatusCode::FORBIDDEN || status == reqwest::StatusCode::NOT_FOUND
+        }
+        None => false,
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthResponse {
     pub token: String,
 }
 
+/// Size of each request in a chunked upload.
+pub const UPLOAD_CHUNK_SIZE: u64 = 8 << 20;
+/// Attempts at each chunk of a chunked upload before giving up.
+pub(crate) const UPLOAD_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct UploadedFile {
+    name: String,
+    pub(crate) id: String,
+    size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct UploadedBytes {
+    #[serde(rename = "uploadedBytes")]
+    pub(crate) uploaded_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountInfo {
+    pub email: String,
+    #[serde(default)]
+    pub name: String,
+    pub usage: i64,
+    pub total: i64,
+}
+
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct Library {
-    permission: String,
-    encrypted: bool,
+    pub permission: String,
+    pub encrypted: bool,
     pub mtime: u64,
-    owner: String,
+    pub owner: String,
     pub id: String,
     pub size: u64,
     pub name: String,
@@ -28,207 +306,488 @@ pub struct Library {
     #[serde(default)]
     desc: String,
     root: String,
+    /// Latest commit, which changes with every change to the library
+    #[serde(default)]
+    pub head_commit_id: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct LibraryEntry {
-    id: String,
+    pub id: String,
     pub mtime: u64,
     #[serde(default)]
     pub size: u64,
     pub name: String,
-    permission: String,
+    pub permission: String,
     #[serde(rename = "type")]
     pub entry_type: String,
+    #[serde(default)]
+    pub modifier_email: String,
+    #[serde(default)]
+    pub modifier_name: String,
+    #[serde(default)]
+    pub is_locked: bool,
+    #[serde(default)]
+    pub locked_by_me: bool,
+    #[serde(default)]
+    pub lock_owner: String,
+}
+
+/// A past revision of a file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileRevision {
+    /// The commit the revision was made in
+    pub id: String,
+    pub rev_file_id: String,
+    #[serde(default)]
+    pub rev_file_size: u64,
+    pub ctime: u64,
+    #[serde(default)]
+    pub creator_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct FileHistory {
+    pub(crate) commits: Vec<FileRevision>,
+}
+
+/// A commit in the history of a library.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Commit {
+    pub id: String,
+    pub ctime: u64,
+    #[serde(default)]
+    pub creator_name: String,
+    #[serde(default)]
+    pub desc: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LibraryHistory {
+    pub(crate) commits: Vec<Commit>,
+    #[serde(default)]
+    pub(crate) page_next: bool,
+}
+
+/// An entry of a directory as it was at some commit.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommitEntry {
+    pub name: String,
+    #[serde(default)]
+    pub is_dir: bool,
+    #[serde(default)]
+    pub size: u64,
+    pub obj_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CommitDir {
+    pub(crate) dirent_list: Vec<CommitEntry>,
 }
 
+/// An entry in the trash of a library.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrashItem {
+    pub obj_name: String,
+    pub obj_id: String,
+    pub parent_dir: String,
+    #[serde(default)]
+    pub is_dir: bool,
+    #[serde(default)]
+    pub size: u64,
+    /// The commit in which the entry last existed
+    pub commit_id: String,
+    /// When the entry was deleted, as an RFC 3339 date
+    pub deleted_time: String,
+}
+
+impl TrashItem {
+    /// Path of the entry before it was deleted.
+    pub fn path(&self) -> PathBuf {
+        Path::new("/").join(&self.parent_dir).join(&self.obj_name)
+    }
+
+    /// When the entry was deleted, in seconds since the epoch.
+    pub fn deleted(&self) -> u64 {
+        chrono::DateTime::parse_from_rfc3339(&self.deleted_time)
+            .map(|time| time.timestamp().max(0) as u64)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Trash {
+    pub(crate) data: Vec<TrashItem>,
+    #[serde(default)]
+    pub(crate) more: bool,
+    #[serde(default)]
+    pub(crate) scan_stat: Option<String>,
+}
+
+/// A link sharing a file or directory with anyone who has it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShareLink {
+    pub token: String,
+    pub link: String,
+    pub path: String,
+    #[serde(default)]
+    pub is_dir: bool,
+    #[serde(default)]
+    pub expire_date: Option<String>,
+    #[serde(default)]
+    pub is_expired: bool,
+}
+
+/// A link letting anyone who has it upload files into a directory.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadLink {
+    pub token: String,
+    pub link: String,
+    pub path: String,
+    #[serde(default)]
+    pub expire_date: Option<String>,
+    #[serde(default)]
+    pub is_expired: bool,
+}
+
+/// The Seafile API for blocking callers: `AsyncSeafileAPI`, run to completion on the shared
+/// runtime. Not for use from within the runtime's own tasks, which use `async_api` instead.
 #[derive(Debug)]
 pub struct SeafileAPI {
-    client: reqwest::blocking::Client,
-    authorization: Mutex<Option<String>>,
-    libraries: Mutex<Option<Vec<Library>>>,
-    server: String,
-    username: String,
-    password: String,
+    api: Arc<AsyncSeafileAPI>,
 }
 
 impl SeafileAPI {
     pub fn new(server: &str, username: &str, password: &str) -> Self {
-        Self {
-            client: reqwest::blocking::Client::new(),
-            authorization: Mutex::new(None),
-            libraries: Mutex::new(None),
-            server: server.to_string(),
-            username: username.to_string(),
-            password: password.to_string(),
-        }
+        Self::with_options(server, username, password, ClientOptions::default())
+            .expect("HTTP client with default options")
     }
 
-    fn login(&self) -> Result<String> {
-        {
-            let auth = self.authorization.lock().unwrap();
-            if let Some(a) = &*auth {
-                return Ok(a.to_string());
-            };
-        }
-        let params = [("username", &self.username), ("password", &self.password)];
-        let url = format!("{}/api2/auth-token/", self.server);
-        let res = self.client.post(url).form(&params).send()?;
-        let body: AuthResponse = res.json()?;
-
-        println!("Body:\n\n{:#?}", body);
+    pub fn with_options(
+        server: &str,
+        username: &str,
+        password: &str,
+        options: ClientOptions,
+    ) -> Result<Self> {
+        let api = AsyncSeafileAPI::with_options(server, username, password, options)?;
+        Ok(Self { api: Arc::new(api) })
+    }
 
-        let mut authorization = String::from("Token ");
-        authorization.push_str(&body.token);
+    /// The same API, sharing its session, for tasks on the runtime.
+    pub fn async_api(&self) -> &Arc<AsyncSeafileAPI> {
+        &self.api
+    }
 
-        debug!("Authorization: {}", authorization);
-        {
-            let mut auth = self.authorization.lock().unwrap();
-            *auth = Some(authorization.clone());
-        }
+    pub fn username(&self) -> &str {
+        self.api.username()
+    }
 
-        Ok(authorization)
+    /// Whether the server was reachable when last tried.
+    pub fn is_online(&self) -> bool {
+        self.api.is_online()
     }
 
-    pub fn get_libraries(&self) -> Result<Vec<Library>> {
-        {
-            let libraries = self.libraries.lock().unwrap();
-            if let Some(l) = &*libraries {
-                return Ok(l.to_vec());
-            }
-        }
-        debug!("self: {:?}", &self);
-        let authorization = self.login()?;
-        debug!("self: {:?}", &self);
-        let url = format!("{}/api2/repos/", self.server);
-        let res = self
-            .client
-            .get(url)
-            .header("Authorization", &authorization)
-            .send()?;
-        debug!("response headers: {:?}", res.headers());
-        let body: Vec<Library> = res.json()?;
-        {
-            let mut libraries = self.libraries.lock().unwrap();
-            *libraries = Some(body.clone());
-        }
-        Ok(body)
+    pub fn ping(&self) -> Result<()> {
+        runtime().block_on(self.api.ping())
     }
 
-    pub fn get_library_content(&self, id: &str, path: &Path) -> Result<Vec<LibraryEntry>> {
-        debug!("self: {:?}", &self);
-        let authorization = self.login()?;
-        debug!("self: {:?}", &self);
-        let url = format!("{}/api2/repos/{}/dir/", self.server, id);
+    pub fn get_account_info(&self) -> Result<AccountInfo> {
+        runtime().block_on(self.api.get_account_info())
+    }
 
-        debug!("url: {}, p: {:?}, {:?}", url, path, [("p", path)]);
+    pub fn get_libraries(&self) -> Result<Vec<Library>> {
+        runtime().block_on(self.api.get_libraries())
+    }
 
-        let res = self
-            .client
-            .get(&url)
-            //.query(&[("t","f"),("p","/")])
-            .query(&[("p", path)])
-            .header("Authorization", &authorization)
-            .send()?;
+    /// Fetch the libraries afresh, replacing those `get_libraries` keeps.
+    pub fn refresh_libraries(&self) -> Result<Vec<Library>> {
+        runtime().block_on(self.api.refresh_libraries())
+    }
 
-        let body: Vec<LibraryEntry> = res.json()?;
-        Ok(body)
+    pub fn get_library_content(&self, id: &str, path: &Path) -> Result<Vec<LibraryEntry>> {
+        runtime().block_on(self.api.get_library_content(id, path))
     }
 
     pub fn create_file(&self, id: &str, path: &Path) -> Result<String> {
-        debug!("self: {:?}", &self);
-        let authorization = self.login()?;
-        debug!("self: {:?}", &self);
-        let url = format!("{}/api2/repos/{}/file/", self.server, id);
-
-        debug!("url: {}, p: {:?}, {:?}", url, path, [("p", path)]);
-
-        let res = self
-            .client
-            .post(&url)
-            .body("operation=create")
-            .header(
-                reqwest::header::CONTENT_TYPE,
-                "application/x-www-form-urlencoded",
-            )
-            //.query(&[("t","f"),("p","/")])
-            .query(&[("p", path)])
-            .header("Authorization", &authorization)
-            .send()?;
-
-        let body: String = res.text()?;
-        Ok(body)
+        runtime().block_on(self.api.create_file(id, path))
     }
 
     pub fn create_new_directory(&self, id: &str, path: &Path) -> Result<String> {
-        debug!("self: {:?}", &self);
-        let authorization = self.login()?;
-        debug!("self: {:?}", &self);
-        let url = format!("{}/api2/repos/{}/dir/", self.server, id);
-
-        debug!("url: {}, p: {:?}, {:?}", url, path, [("p", path)]);
-
-        let res = self
-            .client
-            .post(&url)
-            .body("operation=mkdir")
-            .header(
-                reqwest::header::CONTENT_TYPE,
-                "application/x-www-form-urlencoded",
-            )
-            //.query(&[("t","f"),("p","/")])
-            .query(&[("p", path)])
-            .header("Authorization", &authorization)
-            .send()?;
-
-        let body: String = res.text()?;
-        Ok(body)
+        runtime().block_on(self.api.create_new_directory(id, path))
     }
 
     pub fn delete_directory(&self, id: &str, path: &Path) -> Result<String> {
-        debug!("self: {:?}", &self);
-        let authorization = self.login()?;
-        debug!("self: {:?}", &self);
-        let url = format!("{}/api2/repos/{}/dir/", self.server, id);
+        runtime().block_on(self.api.delete_directory(id, path))
+    }
 
-        debug!("url: {}, p: {:?}, {:?}", url, path, [("p", path)]);
+    pub fn delete_file(&self, id: &str, path: &Path) -> Result<String> {
+        runtime().block_on(self.api.delete_file(id, path))
+    }
 
-        let res = self
-            .client
-            .delete(&url)
-            .query(&[("p", path)])
-            .header("Authorization", &authorization)
-            .send()?;
+    /// Lock the file at `path` against changes by anyone else (Seafile Professional).
+    pub fn lock_file(&self, id: &str, path: &Path) -> Result<()> {
+        runtime().block_on(self.api.lock_file(id, path))
+    }
 
-        let body: String = res.text()?;
-        Ok(body)
+    pub fn unlock_file(&self, id: &str, path: &Path) -> Result<()> {
+        runtime().block_on(self.api.unlock_file(id, path))
     }
 
     pub fn get_download_link(&self, id: &str, path: &Path) -> Result<String> {
-        debug!("self: {:?}", &self);
-        let authorization = self.login()?;
-        debug!("self: {:?}", &self);
-        let url = format!("{}/api2/repos/{}/file/", self.server, id);
+        runtime().block_on(self.api.get_download_link(id, path))
+    }
+
+    /// Download link for the file at `path` with id `file_id`, reused for as long as it lasts.
+    pub fn get_file_link(&self, id: &str, path: &Path, file_id: &str) -> Result<String> {
+        runtime().block_on(self.api.get_file_link(id, path, file_id))
+    }
+
+    /// Download `size` bytes starting at `offset` of the file at `path` with id `file_id`,
+    /// through a reused download link, asking for a new link if the fileserver refuses it.
+    pub fn download_file_range(
+        &self,
+        id: &str,
+        path: &Path,
+        file_id: &str,
+        offset: u64,
+        size: u64,
+    ) -> Result<Bytes> {
+        runtime().block_on(
+            self.api
+                .download_file_range(id, path, file_id, offset, size),
+        )
+    }
+
+    /// Revisions of the file at `path`, newest first.
+    pub fn get_file_history(&self, id: &str, path: &Path) -> Result<Vec<FileRevision>> {
+        runtime().block_on(self.api.get_file_history(id, path))
+    }
+
+    /// Download link for the file at `path` as it was in the commit `commit_id`.
+    pub fn get_file_revision_link(&self, id: &str, path: &Path, commit_id: &str) -> Result<String> {
+        runtime().block_on(self.api.get_file_revision_link(id, path, commit_id))
+    }
+
+    /// Commits of the library, newest first.
+    pub fn get_library_history(&self, id: &str) -> Result<Vec<Commit>> {
+        runtime().block_on(self.api.get_library_history(id))
+    }
+
+    /// Entries of the directory at `path` as it was in the commit `commit_id`.
+    pub fn get_commit_dir(
+        &self,
+        id: &str,
+        commit_id: &str,
+        path: &Path,
+    ) -> Result<Vec<CommitEntry>> {
+        runtime().block_on(self.api.get_commit_dir(id, commit_id, path))
+    }
+
+    /// Everything in the trash of the library.
+    pub fn get_trash(&self, id: &str) -> Result<Vec<TrashItem>> {
+        runtime().block_on(self.api.get_trash(id))
+    }
+
+    /// Restore `item` from the trash to where it was.
+    pub fn restore_trash_item(&self, id: &str, item: &TrashItem) -> Result<()> {
+        runtime().block_on(self.api.restore_trash_item(id, item))
+    }
+
+    /// Rename the file or directory at `path` to `new_name`, within the same directory.
+    pub fn rename(&self, id: &str, path: &Path, is_dir: bool, new_name: &str) -> Result<()> {
+        runtime().block_on(self.api.rename(id, path, is_dir, new_name))
+    }
+
+    /// Move the entry `name` in `parent_dir` into the directory `dst_dir` of the same library.
+    pub fn move_entry(
+        &self,
+        id: &str,
+        parent_dir: &Path,
+        name: &str,
+        dst_dir: &Path,
+    ) -> Result<()> {
+        runtime().block_on(self.api.move_entry(id, parent_dir, name, dst_dir))
+    }
+
+    /// Share links in the library, or only those for the file or directory at `path`.
+    /// Directories are shared as paths ending in `/`.
+    pub fn get_share_links(&self, id: &str, path: Option<&Path>) -> Result<Vec<ShareLink>> {
+        runtime().block_on(self.api.get_share_links(id, path))
+    }
 
-        debug!("url: {}, {:?}, {:?}", url, ("p", path), ("reuse", 1));
+    /// Share the file or directory at `path`, optionally protected by `password` and expiring
+    /// after `expire_days`.
+    pub fn create_share_link(
+        &self,
+        id: &str,
+        path: &Path,
+        password: Option<&str>,
+        expire_days: Option<u32>,
+    ) -> Result<ShareLink> {
+        runtime().block_on(self.api.create_share_link(id, path, password, expire_days))
+    }
+
+    pub fn delete_share_link(&self, token: &str) -> Result<()> {
+        runtime().block_on(self.api.delete_share_link(token))
+    }
+
+    /// Upload links in the library, or only those for the directory at `path`.
+    pub fn get_upload_links(&self, id: &str, path: Option<&Path>) -> Result<Vec<UploadLink>> {
+        runtime().block_on(self.api.get_upload_links(id, path))
+    }
+
+    /// Let anyone with the link upload into the directory at `path`, optionally protected by
+    /// `password` and expiring after `expire_days`.
+    pub fn create_upload_link(
+        &self,
+        id: &str,
+        path: &Path,
+        password: Option<&str>,
+        expire_days: Option<u32>,
+    ) -> Result<UploadLink> {
+        runtime().block_on(self.api.create_upload_link(id, path, password, expire_days))
+    }
 
-        let res = self
-            .client
-            .get(&url)
-            //.query(&[("t","f"),("p","/")])
-            .query(&[("p", path)])
-            .query(&[("reuse", 1)])
-            .header("Authorization", &authorization)
-            .send()?;
+    pub fn delete_upload_link(&self, token: &str) -> Result<()> {
+        runtime().block_on(self.api.delete_upload_link(token))
+    }
 
-        let body: String = res.json()?;
-        Ok(body)
+    /// Start downloading `uri`, to read the body from as it arrives.
+    pub fn download_stream(&self, uri: &str) -> Result<Download> {
+        Ok(Download {
+            res: runtime().block_on(self.api.download_stream(uri))?,
+            api: self.api.clone(),
+            pending: Bytes::new(),
+        })
     }
 
     pub fn download(&self, uri: &str) -> Result<Bytes> {
-        let res = self.client.get(uri).send()?;
-        let body = res.bytes()?;
-        Ok(body)
+        runtime().block_on(self.api.download(uri))
+    }
+
+    /// Download `size` bytes starting at `offset`, or fewer at the end of the file.
+    pub fn download_range(&self, uri: &str, offset: u64, size: u64) -> Result<Bytes> {
+        runtime().block_on(self.api.download_range(uri, offset, size))
+    }
+
+    pub fn get_upload_link(&self, id: &str, parent_dir: &Path) -> Result<String> {
+        runtime().block_on(self.api.get_upload_link(id, parent_dir))
+    }
+
+    pub fn get_update_link(&self, id: &str) -> Result<String> {
+        runtime().block_on(self.api.get_update_link(id))
+    }
+
+    /// Upload `content` as `name` into `parent_dir`, using a link from `get_upload_link`, and
+    /// return the new file id.
+    ///
+    /// `relative_path`, within `parent_dir`, is created if missing. An existing file is
+    /// replaced if `replace`, otherwise the server picks a new name for the upload.
+    pub fn upload_file(
+        &self,
+        upload_link: &str,
+        parent_dir: &Path,
+        relative_path: Option<&Path>,
+        name: &str,
+        content: Bytes,
+        replace: bool,
+    ) -> Result<String> {
+        runtime().block_on(self.api.upload_file(
+            upload_link,
+            parent_dir,
+            relative_path,
+            name,
+            content,
+            replace,
+        ))
+    }
+
+    /// Replace the contents of `target_file` with `content`, using a link from
+    /// `get_update_link`, and return the new file id.
+    pub fn update_file(
+        &self,
+        update_link: &str,
+        target_file: &Path,
+        content: Bytes,
+    ) -> Result<String> {
+        runtime().block_on(self.api.update_file(update_link, target_file, content))
+    }
+
+    /// Bytes of `name` in `parent_dir` the server already holds from an interrupted chunked
+    /// upload.
+    pub fn get_uploaded_bytes(&self, id: &str, parent_dir: &Path, name: &str) -> Result<u64> {
+        runtime().block_on(self.api.get_uploaded_bytes(id, parent_dir, name))
+    }
+
+    /// Upload `size` bytes from `source` as `name` into `parent_dir`, as
+    /// `AsyncSeafileAPI::upload_file_chunked` does.
+    pub fn upload_file_chunked<R: Read + Seek>(
+        &self,
+        id: &str,
+        parent_dir: &Path,
+        name: &str,
+        source: &mut R,
+        size: u64,
+        replace: bool,
+    ) -> Result<String> {
+        let mut source = Blocking(source);
+        runtime().block_on(self.api.upload_file_chunked(
+            id,
+            parent_dir,
+            name,
+            &mut source,
+            size,
+            replace,
+        ))
+    }
+}
+
+/// A download in progress, read as its body arrives.
+pub struct Download {
+    api: Arc<AsyncSeafileAPI>,
+    res: reqwest::Response,
+    /// What arrived and has not been read yet
+    pending: Bytes,
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            match runtime().block_on(self.api.chunk(&mut self.res)) {
+                Ok(Some(chunk)) => self.pending = chunk,
+                Ok(None) => return Ok(0),
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending.split_to(n));
+        Ok(n)
+    }
+}
+
+/// A blocking reader as an async one, for the futures `SeafileAPI` blocks on: they are polled
+/// on the caller's own thread, so reading there holds up no one else.
+struct Blocking<R>(R);
+
+impl<R: Read + Unpin> AsyncRead for Blocking<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = self.0.read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: Seek + Unpin> AsyncSeek for Blocking<R> {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        self.0.seek(position).map(drop)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(self.0.stream_position())
     }
 }
 
@@ -319,3 +878,80 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+    use reqwest::StatusCode;
+
+    fn retry_after_header(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        let options = ClientOptions {
+            retry_delay: Duration::from_millis(100),
+            ..ClientOptions::default()
+        };
+        for attempt in 0..4 {
+            let full = Duration::from_millis(100 << attempt);
+            for _ in 0..100 {
+                let delay = options.backoff(attempt);
+                assert!(delay <= full, "{:?} over {:?}", delay, full);
+                assert!(delay >= full / 2, "{:?} under half of {:?}", delay, full);
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let options = ClientOptions {
+            retry_delay: Duration::from_secs(1),
+            ..ClientOptions::default()
+        };
+        for attempt in [6, 16, 40, u32::MAX] {
+            let delay = options.backoff(attempt);
+            assert!(delay <= MAX_RETRY_DELAY);
+            assert!(delay >= MAX_RETRY_DELAY / 2);
+        }
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        let headers = retry_after_header("120");
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+        let headers = retry_after_header(" 3 ");
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn retry_after_as_date() {
+        let date = chrono::Utc::now() + chrono::Duration::seconds(90);
+        let headers = retry_after_header(&date.to_rfc2822());
+        let delay = retry_after(&headers).unwrap();
+        assert!(delay <= Duration::from_secs(90));
+        assert!(delay >= Duration::from_secs(85));
+    }
+
+    #[test]
+    fn retry_after_past_or_invalid() {
+        let date = chrono::Utc::now() - chrono::Duration::seconds(90);
+        assert_eq!(retry_after(&retry_after_header(&date.to_rfc2822())), None);
+        assert_eq!(retry_after(&retry_after_header("soon")), None);
+        assert_eq!(retry_after(&retry_after_header("-1")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn retryable_statuses() {
+        assert!(is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_retryable_status(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!is_retryable_status(StatusCode::NOT_FOUND));
+        assert!(!is_retryable_status(StatusCode::FORBIDDEN));
+    }
+}
@@ -0,0 +1,95 @@
+//! Read-only `.snapshots` directories, holding whole libraries as they were at past commits.
+//!
+//! `/<library>/.snapshots/<timestamp>/` is the library as of the commit made at `<timestamp>`.
+//! The directory is not listed, but can be entered by name.
+
+use crate::history::timestamp;
+use crate::resolve::{errno, Location};
+use crate::seafileapi::{Commit, CommitEntry, Library};
+use crate::SeafileFS;
+use libc::{c_int, ENOENT};
+use log::debug;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const SNAPSHOTS_DIR: &str = ".snapshots";
+
+impl SeafileFS {
+    /// Commits of the library `library_id`, newest first, through the metadata cache. Only the
+    /// newest of several made in the same second is kept, so each has its own name.
+    pub(crate) fn snapshots(&self, library_id: &str) -> Result<Arc<Vec<Commit>>, c_int> {
+        if let Some(commits) = self.commits.get(&library_id.to_string()) {
+            return Ok(commits);
+        }
+        let mut commits = self.api.get_library_history(library_id).map_err(|e| {
+            debug!("ERROR: get_library_history({:?}) {}", library_id, e);
+            errno(&e)
+        })?;
+        commits.dedup_by_key(|commit| commit.ctime);
+        let commits = Arc::new(commits);
+        self.commits.insert(library_id.to_string(), commits.clone());
+        Ok(commits)
+    }
+
+    /// Listing of the directory `path` as of the commit `commit_id`, through the metadata cache.
+    pub(crate) fn list_snapshot(
+        &self,
+        library_id: &str,
+        commit_id: &str,
+        path: &Path,
+    ) -> Result<Arc<Vec<CommitEntry>>, c_int> {
+        let key = (
+            library_id.to_string(),
+            commit_id.to_string(),
+            path.to_path_buf(),
+        );
+        if let Some(entries) = self.snapshot_directories.get(&key) {
+            return Ok(entries);
+        }
+        let entries = self
+            .api
+            .get_commit_dir(library_id, commit_id, path)
+            .map_err(|e| {
+                debug!("ERROR: get_commit_dir({:?}, {:?}) {}", commit_id, path, e);
+                errno(&e)
+            })?;
+        let entries = Arc::new(entries);
+        self.snapshot_directories.insert(key, entries.clone());
+        Ok(entries)
+    }
+
+    /// Resolve the components `rest` following `/<library>/.snapshots`.
+    pub(crate) fn resolve_snapshot(
+        &self,
+        library: Library,
+        rest: &[&OsStr],
+    ) -> Result<Location, c_int> {
+        let name = match rest {
+            [] => return Ok(Location::Snapshots(library)),
+            [name, ..] => name.to_string_lossy(),
+        };
+        let commit = self
+            .snapshots(&library.id)?
+            .iter()
+            .find(|commit| timestamp(commit.ctime) == name)
+            .cloned()
+            .ok_or(ENOENT)?;
+        let path = Path::new("/").join(rest[1..].iter().collect::<PathBuf>());
+        let entry = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => {
+                let name = name.to_string_lossy();
+                let entries = self.list_snapshot(&library.id, &commit.id, parent)?;
+                let entry = entries.iter().find(|entry| entry.name == name);
+                Some(entry.cloned().ok_or(ENOENT)?)
+            }
+            _ => None,
+        };
+        Ok(Location::Snapshot {
+            library,
+            commit,
+            path,
+            entry,
+        })
+    }
+}
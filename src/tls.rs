@@ -0,0 +1,157 @@
+//! TLS set up for the HTTP client: trusted authorities, client certificates, and pinned
+//! server certificates, checked as each connection is made, before any request is sent on it.
+
+use crate::seafileapi::{ClientOptions, PinMismatch, Result};
+use log::warn;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, CertificateError, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use std::error::Error as _;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// SHA-256 fingerprint of `certificate`, in lowercase hex.
+pub(crate) fn fingerprint(certificate: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, certificate)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Verifies server certificates as usual, unless `insecure`, and then that they are pinned,
+/// if any are.
+struct Verifier {
+    /// Verification against the trusted authorities, unless `insecure`
+    webpki: Option<WebPkiVerifier>,
+    pinned: Vec<String>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if self.pinned.is_empty() {
+            return Ok(ServerCertVerified::assertion());
+        }
+        let fingerprint = fingerprint(&end_entity.0);
+        match self.pinned.contains(&fingerprint) {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                Arc::new(PinMismatch { fingerprint }),
+            ))),
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    Ok(BufReader::new(
+        File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?,
+    ))
+}
+
+/// The TLS configuration for `options`.
+pub(crate) fn config(options: &ClientOptions) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certificates) => {
+            let certificates = certificates.into_iter().map(|c| c.0).collect::<Vec<_>>();
+            roots.add_parsable_certificates(&certificates);
+        }
+        Err(e) => warn!("cannot load the system's certificate authorities: {}", e),
+    }
+    if let Some(ca_file) = &options.ca_file {
+        let certificates = rustls_pemfile::certs(&mut open(ca_file)?)?;
+        if certificates.is_empty() {
+            return Err(format!("{}: no certificates", ca_file.display()).into());
+        }
+        for certificate in certificates {
+            roots.add(&Certificate(certificate))?;
+        }
+    }
+
+    let webpki = match options.insecure {
+        true => {
+            warn!("INSECURE: server certificates are not verified");
+            None
+        }
+        false => Some(WebPkiVerifier::new(roots.clone(), None)),
+    };
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let mut config = match (&options.client_cert, &options.client_key) {
+        (Some(cert), Some(key)) => {
+            let chain = rustls_pemfile::certs(&mut open(cert)?)?
+                .into_iter()
+                .map(Certificate)
+                .collect::<Vec<_>>();
+            let key = rustls_pemfile::pkcs8_private_keys(&mut open(key)?)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| format!("{}: no PKCS#8 key", key.display()))?;
+            builder.with_client_auth_cert(chain, PrivateKey(key))?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err("client_cert and client_key go together".into()),
+    };
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(Verifier {
+            webpki,
+            pinned: options.pinned.clone(),
+        }));
+    Ok(config)
+}
+
+/// Whether `e` is down to the server's certificate being refused, rather than to the server
+/// being unreachable.
+pub(crate) fn is_certificate_error(e: &reqwest::Error) -> bool {
+    let mut source = e.source();
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<rustls::Error>() {
+            return matches!(error, rustls::Error::InvalidCertificate(_));
+        }
+        // The TLS error comes wrapped in I/O errors, which do not give it as their source
+        source = match error.downcast_ref::<io::Error>() {
+            Some(error) => error.get_ref().map(|error| error as _),
+            None => error.source(),
+        };
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_is_lowercase_sha256_hex() {
+        assert_eq!(
+            fingerprint(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            fingerprint(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Read-only `.trash` directories, holding the entries deleted from each library.
+//!
+//! `/<library>/.trash/<name>` is a deleted file or directory, named as it was, or with the time
+//! it was deleted added when several share a name. Moving one out of the trash restores it.
+//! The directory is not listed, but can be entered by name.
+
+use crate::history::timestamp;
+use crate::resolve::{errno, Location};
+use crate::seafileapi::{Commit, CommitEntry, Library, TrashItem};
+use crate::SeafileFS;
+use libc::{c_int, ENOENT, EXDEV};
+use log::debug;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const TRASH_DIR: &str = ".trash";
+
+impl SeafileFS {
+    /// The trash of the library `library_id` with the name of each entry, through the metadata
+    /// cache.
+    pub(crate) fn trash(&self, library_id: &str) -> Result<Arc<Vec<(String, TrashItem)>>, c_int> {
+        if let Some(items) = self.trash.get(&library_id.to_string()) {
+            return Ok(items);
+        }
+        let items = self.api.get_trash(library_id).map_err(|e| {
+            debug!("ERROR: get_trash({:?}) {}", library_id, e);
+            errno(&e)
+        })?;
+        let mut counts = HashMap::new();
+        for item in &items {
+            *counts.entry(item.obj_name.clone()).or_insert(0) += 1;
+        }
+        let items = items
+            .into_iter()
+            .map(|item| match counts[&item.obj_name] {
+                1 => (item.obj_name.clone(), item),
+                _ => (
+                    format!("{} (deleted {})", item.obj_name, timestamp(item.deleted())),
+                    item,
+                ),
+            })
+            .collect::<Vec<_>>();
+        let items = Arc::new(items);
+        self.trash.insert(library_id.to_string(), items.clone());
+        Ok(items)
+    }
+
+    /// The entry named `name` in the trash of `library`.
+    fn trash_item(&self, library: &Library, name: &OsStr) -> Result<TrashItem, c_int> {
+        let name = name.to_string_lossy();
+        self.trash(&library.id)?
+            .iter()
+            .find(|(item_name, _)| *item_name == name)
+            .map(|(_, item)| item.clone())
+            .ok_or(ENOENT)
+    }
+
+    /// Resolve the components `rest` following `/<library>/.trash`. Deleted entries are read
+    /// as of the last commit they existed in, like snapshots.
+    pub(crate) fn resolve_trash(
+        &self,
+        library: Library,
+        rest: &[&OsStr],
+    ) -> Result<Location, c_int> {
+        let item = match rest {
+            [] => return Ok(Location::Trash(library)),
+            [name, ..] => self.trash_item(&library, name)?,
+        };
+        let commit = Commit {
+            id: item.commit_id.clone(),
+            ctime: item.deleted(),
+            creator_name: String::new(),
+            desc: String::new(),
+        };
+        let mut path = item.path();
+        path.extend(&rest[1..]);
+        let entry = match rest {
+            [_] => CommitEntry {
+                name: item.obj_name.clone(),
+                is_dir: item.is_dir,
+                size: item.size,
+                obj_id: item.obj_id.clone(),
+            },
+            _ => {
+                let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                let entries = self.list_snapshot(&library.id, &commit.id, parent)?;
+                let entry = entries.iter().find(|entry| entry.name == name);
+                entry.cloned().ok_or(ENOENT)?
+            }
+        };
+        Ok(Location::Snapshot {
+            library,
+            commit,
+            path,
+            entry: Some(entry),
+        })
+    }
+
+    /// Restore the entry `name` from the trash of `library` to `destination`, which must be in
+    /// the same library.
+    pub(crate) fn restore(
+        &self,
+        library: &Library,
+        name: &OsStr,
+        destination: (Library, PathBuf),
+    ) -> Result<(), c_int> {
+        let (target_library, target) = destination;
+        if target_library.id != library.id {
+            return Err(EXDEV);
+        }
+        let item = self.trash_item(library, name)?;
+        let original = item.path();
+        debug!("restore {:?} from trash to {:?}", original, target);
+        self.api
+            .restore_trash_item(&library.id, &item)
+            .map_err(|e| {
+                debug!("ERROR: restore_trash_item({:?}) {}", original, e);
+                errno(&e)
+            })?;
+        self.trash.remove(&library.id);
+        self.invalidate_parent(&library.id, &original);
+
+        let root = Path::new("/");
+        let original_dir = original.parent().unwrap_or(root);
+        let target_dir = target.parent().unwrap_or(root);
+        if target_dir != original_dir {
+            self.api
+                .move_entry(&library.id, original_dir, &item.obj_name, target_dir)
+                .map_err(|e| {
+                    debug!("ERROR: move_entry({:?}) {}", original, e);
+                    errno(&e)
+                })?;
+            self.invalidate_parent(&library.id, &target);
+        }
+        let target_name = target.file_name().unwrap_or_default().to_string_lossy();
+        if target_name != item.obj_name {
+            let moved = target_dir.join(&item.obj_name);
+            self.api
+                .rename(&library.id, &moved, item.is_dir, &target_name)
+                .map_err(|e| {
+                    debug!("ERROR: rename({:?}) {}", moved, e);
+                    errno(&e)
+                })?;
+            self.invalidate_parent(&library.id, &target);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+//! Writing through local copies which are uploaded on flush.
+
+use crate::cache::BLOCK_SIZE;
+use crate::handle::Handle;
+use crate::journal::{Operation, PENDING_ID};
+use crate::options::ConflictPolicy;
+use crate::resolve::errno;
+use crate::seafileapi::is_offline;
+use crate::SeafileFS;
+use chrono::Local;
+use libc::{c_int, EIO};
+use log::debug;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+fn io_errno(e: std::io::Error) -> c_int {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+/// `name (SFConflict user date).ext` beside `path`, as the Seafile clients name conflict copies.
+pub(crate) fn conflict_copy(path: &Path, user: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let date = Local::now().format("%Y-%m-%d-%H-%M-%S");
+    let name = match path.extension() {
+        Some(extension) => format!(
+            "{} (SFConflict {} {}).{}",
+            stem,
+            user,
+            date,
+            extension.to_string_lossy()
+        ),
+        None => format!("{} (SFConflict {} {})", stem, user, date),
+    };
+    path.with_file_name(name)
+}
+
+impl SeafileFS {
+    /// Give `handle` a local copy to write to, holding the current contents unless `truncate`.
+    pub(crate) fn stage(&self, handle: &mut Handle, truncate: bool) -> Result<(), c_int> {
+        if handle.staging.is_some() {
+            return Ok(());
+        }
+        let path = handle.path();
+        let staging = tempfile::tempfile().map_err(io_errno)?;
+        if !truncate {
+            let entry = self.stat(&handle.library, &path)?;
+            // Changes made since the handle was opened are in the copy, so are not conflicts
+            handle.base_id = Some(entry.id.clone());
+            let mut offset = 0;
+            while offset < entry.size {
+                let block = self
+                    .read_blocks(&handle.library.id, &path, &entry, offset, BLOCK_SIZE)
+                    .map_err(|e| {
+                        debug!("ERROR: stage({:?}) {}", path, e);
+                        errno(&e)
+                    })?;
+                if block.is_empty() {
+                    break;
+                }
+                staging.write_all_at(&block, offset).map_err(io_errno)?;
+                offset += block.len() as u64;
+            }
+        }
+        debug!("staged {:?} (truncate: {})", path, truncate);
+        handle.set_staging(staging).map_err(io_errno)
+    }
+
+    /// Whether the file of `handle` changed on the server since it was opened or last
+    /// uploaded through it.
+    fn changed_elsewhere(&self, handle: &Handle) -> Result<bool, c_int> {
+        let base_id = match &handle.base_id {
+            // Ids of contents still in the journal are not known to the server
+            Some(base_id) if !base_id.starts_with(PENDING_ID) => base_id,
+            _ => return Ok(false),
+        };
+        let path = handle.path();
+        let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        // Straight from the server: a cached listing may predate the change
+        let entries = match self.api.get_library_content(&handle.library.id, parent) {
+            Ok(entries) => entries,
+            // There is no telling; the upload is left in the journal, to be checked on replay
+            Err(e) if is_offline(&e) => return Ok(false),
+            Err(e) => {
+                debug!("ERROR: write_back({:?}) {}", path, e);
+                return Err(errno(&e));
+            }
+        };
+        Ok(entries
+            .iter()
+            .any(|entry| entry.name == name && entry.id != *base_id))
+    }
+
+    /// Upload the local copy of `handle`, if it has changed, through the journal.
+    ///
+    /// If someone else changed the file meanwhile, the mount's conflict policy decides whether
+    /// to upload over it, beside it as a conflict copy (which the handle then refers to), or
+    /// not at all.
+    pub(crate) fn write_back(&self, handle: &mut Handle) -> Result<(), c_int> {
+        if handle.staging.is_none() || !handle.dirty {
+            return Ok(());
+        }
+        let changed = self.changed_elsewhere(handle)?;
+        let copy = match changed {
+            false => false,
+            true => {
+                debug!("write_back: {:?} changed on the server", handle.path());
+                match self.options.conflict {
+                    ConflictPolicy::Copy => true,
+                    ConflictPolicy::Fail => return Err(EIO),
+                    ConflictPolicy::Overwrite => false,
+                }
+            }
+        };
+        if copy {
+            handle.set_path(conflict_copy(&handle.path(), self.api.username()));
+        }
+
+        let library_id = handle.library.id.clone();
+        let path = handle.path();
+        let staging = handle.staging.as_mut().ok_or(libc::EBADF)?;
+        debug!("write_back: {:?}", path);
+        let operation = Operation::Upload {
+            path: path.clone(),
+            replace: !copy,
+            // Checked again if the upload is replayed, as it was not checked offline
+            base_id: match changed {
+                false => handle
+                    .base_id
+                    .clone()
+                    .filter(|id| !id.starts_with(PENDING_ID)),
+                true => None,
+            },
+        };
+        let id = self.journaled(&library_id, operation, Some(staging))?;
+        debug!("write_back: {:?} is now {:?}", path, id);
+        handle.dirty = false;
+        // Not known until the upload is replayed, if it was queued
+        handle.base_id = id;
+        self.invalidate_parent(&library_id, &path);
+        Ok(())
+    }
+
+    /// Read from the local copy of `handle`.
+    pub(crate) fn read_staged(
+        handle: &Handle,
+        offset: u64,
+        size: u32,
+    ) -> Option<Result<Vec<u8>, c_int>> {
+        let staging = handle.staging.as_ref()?;
+        let mut buffer = vec![0; size as usize];
+        let mut read = 0;
+        while read < buffer.len() {
+            match staging.read_at(&mut buffer[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) => return Some(Err(io_errno(e))),
+            }
+        }
+        buffer.truncate(read);
+        Some(Ok(buffer))
+    }
+
+    pub(crate) fn write_staged(
+        handle: &mut Handle,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<u32, c_int> {
+        let staging = handle.staging.as_ref().ok_or(libc::EBADF)?;
+        staging.write_all_at(data, offset).map_err(io_errno)?;
+        handle.dirty = true;
+        Ok(data.len() as u32)
+    }
+
+    pub(crate) fn truncate_staged(handle: &mut Handle, size: u64) -> Result<(), c_int> {
+        let staging = handle.staging.as_ref().ok_or(libc::EBADF)?;
+        staging.set_len(size).map_err(io_errno)?;
+        handle.dirty = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `copy` with the date, which changes from run to run, taken out.
+    fn without_date(copy: &Path) -> String {
+        let name = copy.to_string_lossy();
+        let (before, after) = name.rsplit_once(' ').unwrap();
+        let (date, after) = after.split_once(')').unwrap();
+        assert!(
+            chrono::NaiveDateTime::parse_from_str(date, "%Y-%m-%d-%H-%M-%S").is_ok(),
+            "{:?}",
+            date
+        );
+        format!("{} <date>){}", before, after)
+    }
+
+    #[test]
+    fn conflict_copy_keeps_the_extension() {
+        let copy = conflict_copy(Path::new("/docs/report.txt"), "bob@example.com");
+        assert_eq!(
+            without_date(&copy),
+            "/docs/report (SFConflict bob@example.com <date>).txt"
+        );
+    }
+
+    #[test]
+    fn conflict_copy_of_names_without_extension() {
+        let copy = conflict_copy(Path::new("/Makefile"), "bob");
+        assert_eq!(without_date(&copy), "/Makefile (SFConflict bob <date>)");
+        let copy = conflict_copy(Path::new("/a/archive.tar.gz"), "bob");
+        assert_eq!(
+            without_date(&copy),
+            "/a/archive.tar (SFConflict bob <date>).gz"
+        );
+    }
+}